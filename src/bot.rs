@@ -0,0 +1,253 @@
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::notify::{Event, Message, Notifier, NotifyChannel};
+
+const TELEGRAM_API: &str = "https://api.telegram.org";
+const SERVER_CHAN_API: &str = "https://sctapi.ftqq.com";
+
+/// 机器人接口在HTTP状态码为200时也可能失败，需要检查响应中fields里第一个存在的字段是否为ok
+fn check_response(text: &str, fields: &[&str], ok: serde_json::Value) -> anyhow::Result<()> {
+    let json: serde_json::Value = serde_json::from_str(text)
+        .with_context(|| format!("机器人返回的不是JSON: {}", text.trim()))?;
+    match fields.iter().find_map(|field| json.get(field)) {
+        Some(value) if *value == ok => Ok(()),
+        _ => anyhow::bail!("机器人返回错误: {}", text.trim()),
+    }
+}
+
+fn hmac_sha256_base64(key: &str, message: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+fn api_base<'a>(address: &'a str, default: &'a str) -> &'a str {
+    match address.trim() {
+        "" => default,
+        address => address.trim_end_matches('/'),
+    }
+}
+
+/// Telegram机器人，令牌为机器人令牌，发送到聊天ID
+pub struct Telegram<'a> {
+    pub channel: &'a NotifyChannel,
+}
+
+impl Notifier for Telegram<'_> {
+    fn notify(&self, _: &Event, message: &Message) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/bot{}/sendMessage",
+            api_base(&self.channel.地址, TELEGRAM_API),
+            self.channel.令牌
+        );
+        let body = json!({
+            "chat_id": self.channel.聊天ID,
+            "text": format!("{}\n{}", message.title, message.text),
+        });
+        let response = self.channel.webhook(&url).send(&body.to_string())?;
+        check_response(&response, &["ok"], json!(true))
+    }
+}
+
+/// Server酱，令牌为SendKey
+pub struct ServerChan<'a> {
+    pub channel: &'a NotifyChannel,
+}
+
+impl Notifier for ServerChan<'_> {
+    fn notify(&self, _: &Event, message: &Message) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/{}.send",
+            api_base(&self.channel.地址, SERVER_CHAN_API),
+            self.channel.令牌
+        );
+        let body = json!({ "title": message.title, "desp": message.text });
+        let response = self.channel.webhook(&url).send(&body.to_string())?;
+        check_response(&response, &["code"], json!(0))
+    }
+}
+
+/// 钉钉群机器人，设置了签名密钥时在地址后加上timestamp和sign参数
+pub struct DingTalk<'a> {
+    pub channel: &'a NotifyChannel,
+}
+
+impl DingTalk<'_> {
+    fn signed_url(&self, timestamp: i64) -> String {
+        let secret = &self.channel.签名密钥;
+        if secret.is_empty() {
+            return self.channel.地址.clone();
+        }
+        let sign = hmac_sha256_base64(secret, &format!("{}\n{}", timestamp, secret));
+        let separator = if self.channel.地址.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!(
+            "{}{}timestamp={}&sign={}",
+            self.channel.地址,
+            separator,
+            timestamp,
+            urlencoding::encode(&sign)
+        )
+    }
+}
+
+impl Notifier for DingTalk<'_> {
+    fn notify(&self, _: &Event, message: &Message) -> anyhow::Result<()> {
+        let url = self.signed_url(Local::now().timestamp_millis());
+        let body = json!({
+            "msgtype": "text",
+            "text": { "content": format!("{}\n{}", message.title, message.text) },
+        });
+        let response = self.channel.webhook(&url).send(&body.to_string())?;
+        check_response(&response, &["errcode"], json!(0))
+    }
+}
+
+/// 飞书群机器人，设置了签名密钥时在请求体中加上timestamp和sign
+pub struct Feishu<'a> {
+    pub channel: &'a NotifyChannel,
+}
+
+impl Feishu<'_> {
+    fn body(&self, text: String, timestamp: i64) -> serde_json::Value {
+        let mut body = json!({
+            "msg_type": "text",
+            "content": { "text": text },
+        });
+        let secret = &self.channel.签名密钥;
+        if !secret.is_empty() {
+            body["timestamp"] = json!(timestamp.to_string());
+            body["sign"] = json!(hmac_sha256_base64(
+                &format!("{}\n{}", timestamp, secret),
+                ""
+            ));
+        }
+        body
+    }
+}
+
+impl Notifier for Feishu<'_> {
+    fn notify(&self, _: &Event, message: &Message) -> anyhow::Result<()> {
+        let body = self.body(
+            format!("{}\n{}", message.title, message.text),
+            Local::now().timestamp(),
+        );
+        let response = self
+            .channel
+            .webhook(&self.channel.地址)
+            .send(&body.to_string())?;
+        check_response(&response, &["code", "StatusCode"], json!(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_gateway::{MockServer, Response};
+    use crate::notify::tests::test_message;
+    use crate::notify::ChannelType;
+
+    fn event() -> Event {
+        Event::Error {
+            error: "连接失败".to_owned(),
+        }
+    }
+
+    fn channel(type_: ChannelType, address: &str) -> NotifyChannel {
+        NotifyChannel {
+            类型: type_,
+            地址: address.to_owned(),
+            令牌: "token".to_owned(),
+            聊天ID: "42".to_owned(),
+            重试间隔: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn telegram_sends_message() {
+        let server = MockServer::start("", |_| Response::ok(r#"{"ok":true}"#));
+        let channel = channel(ChannelType::Telegram, &server.url);
+        Telegram { channel: &channel }
+            .notify(&event(), &test_message())
+            .unwrap();
+        let request = &server.requests()[0];
+        assert_eq!(request.target, "/bottoken/sendMessage");
+        let json: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(json["chat_id"], "42");
+        assert_eq!(json["text"], "标题\n内容");
+    }
+
+    #[test]
+    fn server_chan_checks_code() {
+        let server = MockServer::start("", |_| Response::ok(r#"{"code":40001,"message":"bad"}"#));
+        let channel = channel(ChannelType::ServerChan, &server.url);
+        let e = ServerChan { channel: &channel }
+            .notify(&event(), &test_message())
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            r#"机器人返回错误: {"code":40001,"message":"bad"}"#
+        );
+        assert_eq!(server.requests()[0].target, "/token.send");
+    }
+
+    #[test]
+    fn dingtalk_signs_url() {
+        let server = MockServer::start("/robot/send?access_token=abc", |_| {
+            Response::ok(r#"{"errcode":0,"errmsg":"ok"}"#)
+        });
+        let channel = NotifyChannel {
+            签名密钥: "SECabc".to_owned(),
+            ..channel(ChannelType::DingTalk, &server.url)
+        };
+        let dingtalk = DingTalk { channel: &channel };
+        dingtalk.notify(&event(), &test_message()).unwrap();
+        let request = &server.requests()[0];
+        assert_eq!(request.param("access_token").unwrap(), "abc");
+        let timestamp: i64 = request.param("timestamp").unwrap().parse().unwrap();
+        // python3 -c "import hmac,hashlib,base64;print(base64.b64encode(hmac.new(b'SECabc',b'1700000000000\nSECabc',hashlib.sha256).digest()).decode())"
+        assert_eq!(
+            dingtalk.signed_url(1_700_000_000_000),
+            format!(
+                "{}&timestamp=1700000000000&sign={}",
+                server.url,
+                urlencoding::encode("jcUpW0QmtKduN03n4JqQ0PBosVjqnM8gU7fIIvsDmCM=")
+            )
+        );
+        assert_eq!(
+            request.param("sign").unwrap(),
+            hmac_sha256_base64("SECabc", &format!("{}\nSECabc", timestamp))
+        );
+        let json: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(json["text"]["content"], "标题\n内容");
+    }
+
+    #[test]
+    fn feishu_signs_body() {
+        let server = MockServer::start("/hook", |_| Response::ok(r#"{"code":0,"msg":"success"}"#));
+        let channel = NotifyChannel {
+            签名密钥: "secret".to_owned(),
+            ..channel(ChannelType::Feishu, &server.url)
+        };
+        let feishu = Feishu { channel: &channel };
+        feishu.notify(&event(), &test_message()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(json["msg_type"], "text");
+        assert_eq!(json["content"]["text"], "标题\n内容");
+        // python3 -c "import hmac,hashlib,base64;print(base64.b64encode(hmac.new(b'1700000000\nsecret',b'',hashlib.sha256).digest()).decode())"
+        let body = feishu.body(String::new(), 1_700_000_000);
+        assert_eq!(body["timestamp"], "1700000000");
+        assert_eq!(body["sign"], "fiWS2+gh28DOydAv7hzONH/mDn9+b1Y4Y5ivXWXy8vA=");
+    }
+}
@@ -0,0 +1,1331 @@
+use std::{fmt::Display, time::Duration};
+
+use chrono::{Local, NaiveDateTime};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::data::{Data, TIME_FORMAT};
+use crate::ddns::update_ddns;
+use crate::error::{is_request_timeout, WltError};
+use crate::ip_provider::{discover_ip, IpFamily};
+use crate::ipv6::get_ipv6;
+use crate::log::{log, log_append};
+use crate::notify::{notify, Event};
+use crate::outbox;
+use crate::policy::active_policy;
+use crate::probe::probe_all;
+use crate::utils::replace_password;
+use crate::wlt::{ControlPageInfo, WltClient, WltPageType};
+
+pub fn new_wlt_client(config: &Config, data: &Data) -> Result<WltClient, WltError> {
+    WltClient::new(
+        &config.网络通网关,
+        &data.网关,
+        &config.网络通用户名,
+        &config.网络通密码,
+        config.网络通出口,
+        config.网络通使用时限,
+        &data.rn,
+    )
+}
+
+/// 登录网络通，rn变化时保存到data中
+fn login_wlt(data: &mut Data, wlt_client: &mut WltClient, ip: &str) -> Result<(), WltError> {
+    wlt_client.login(ip)?;
+    if wlt_client.get_rn() != data.rn {
+        log(format!("旧rn: {} 新rn: {}", data.rn, wlt_client.get_rn()));
+        data.rn = wlt_client.get_rn().to_owned();
+        data.save()?;
+    }
+    Ok(())
+}
+
+/// 距离需要提前续期还有多久，已经需要续期时返回0，没有到期时间（使用时限为永久）时返回None
+pub fn time_until_renewal(data: &Data, advance: u64, now: NaiveDateTime) -> Option<Duration> {
+    let renew_at = data.session_expiry()? - chrono::Duration::seconds(advance as i64);
+    Some((renew_at - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+pub fn check_wlt(
+    config: &Config,
+    data: &mut Data,
+    wlt_client: &mut WltClient,
+) -> anyhow::Result<()> {
+    let now = Local::now().naive_local();
+    let policy = active_policy(&config.出口策略, now)?;
+    let (exit, exp) = match policy {
+        Some(policy) => (policy.出口, policy.使用时限),
+        None => (config.网络通出口, config.网络通使用时限),
+    };
+    // 首选出口不久前探测失败时继续使用备用出口，避免每次检测都切回首选出口再切走
+    let failover = match config.探测目标.is_empty() {
+        true => None,
+        false => data.failover_exit(
+            exit,
+            now,
+            chrono::Duration::seconds(config.首选出口重试间隔 as i64),
+        ),
+    };
+    wlt_client.set_exit(failover.unwrap_or(exit), exp);
+
+    let wlt_page = wlt_client.access_page()?;
+    let mut new_ipv4 = wlt_page.search_ip()?;
+
+    // 出口和设置不同时，开通网络后通知出口变化
+    let mut exit_changed = None;
+    let need_set_wlt = match wlt_page.page_type()? {
+        WltPageType::ControlPage => {
+            let info = wlt_page.control_page_info()?;
+            let type_ = info.exit;
+            // 不是本程序开通的网络没有记录到期时间，从页面上读取
+            if data.到期时间.is_empty() {
+                if let Some(expiry) = info.expiry_time(now) {
+                    data.到期时间 = expiry.format(TIME_FORMAT).to_string();
+                }
+            }
+            if type_ == failover.unwrap_or(exit) {
+                let limit_changed = info.time_limit_secs().is_some_and(|limit| limit != exp);
+                if limit_changed {
+                    log(format!(
+                        "使用时限变化 旧使用时限: {} 新使用时限: {}秒",
+                        info.time_limit.as_deref().unwrap_or_default(),
+                        exp
+                    ));
+                }
+                let need_renew =
+                    time_until_renewal(data, config.续期提前时间, now) == Some(Duration::ZERO);
+                if need_renew && !limit_changed {
+                    log(format!("网络通将于{}到期，提前续期", data.到期时间));
+                }
+                limit_changed || need_renew
+            } else {
+                let reason = match policy {
+                    Some(policy) => format!("出口策略: {}", policy),
+                    None => "与网络通出口的设置不同".to_owned(),
+                };
+                let new_exit = failover.unwrap_or(exit);
+                log(format!("{} 旧出口: {} 新出口: {}", reason, type_, new_exit));
+                // 当前是上次探测选择的备用出口时，是否变化由这次探测决定
+                if config.探测目标.is_empty() || data.出口 != Some(type_) {
+                    exit_changed = Some(Event::ExitChanged {
+                        old_exit: type_,
+                        new_exit,
+                        reason,
+                    });
+                }
+                true
+            }
+        }
+        WltPageType::LoginPage => {
+            if let Err(e) = login_wlt(data, wlt_client, &new_ipv4) {
+                if let WltError::LoginFailed { reason } = &e {
+                    let error = replace_password(reason.clone(), &config.网络通密码, "***");
+                    notify(config, data, &Event::LoginFailed { error });
+                }
+                return Err(e.into());
+            }
+            true
+        }
+    };
+
+    if need_set_wlt {
+        let set_wlt_page = wlt_client.set_wlt()?;
+        data.record_session(now, exp);
+        data.save()?;
+        new_ipv4 = set_wlt_page.search_ip()?;
+        if let Some(event) = exit_changed {
+            notify(config, data, &event);
+        }
+    }
+    if !config.探测目标.is_empty() {
+        probe_exits(config, data, wlt_client, exit, failover, exp, now)?;
+    }
+
+    let old_ipv4 = data.ipv4.clone();
+    let old_ipv6 = data.ipv6.clone();
+    let new_ipv4 = match discover_ip(config, IpFamily::IPv4, &new_ipv4) {
+        Some(Ok(ip)) => ip,
+        Some(Err(e)) => {
+            log(format!("{:#}", e).replace("\n", " "));
+            old_ipv4.clone()
+        }
+        None => new_ipv4,
+    };
+    let new_ipv6 = match config.检测IPv6 {
+        true => match discover_ip(config, IpFamily::IPv6, &new_ipv4) {
+            Some(Ok(ip)) => ip,
+            Some(Err(e)) => {
+                log(format!("{:#}", e).replace("\n", " "));
+                old_ipv6.clone()
+            }
+            None => get_ipv6(config).unwrap_or_else(|_| old_ipv6.clone()),
+        },
+        false => old_ipv6.clone(),
+    };
+    if new_ipv4 != old_ipv4 || new_ipv6 != old_ipv6 {
+        log(format!(
+            "旧IPv4: {} 旧IPv6: {} 新IPv4: {} 新IPv6: {}",
+            old_ipv4, old_ipv6, new_ipv4, new_ipv6
+        ));
+        data.ipv4 = new_ipv4.clone();
+        data.ipv6 = new_ipv6.clone();
+        data.record_ip_change(now);
+        data.save()?;
+        notify(
+            config,
+            data,
+            &Event::IpChanged {
+                old_ipv4,
+                new_ipv4,
+                old_ipv6,
+                new_ipv6,
+            },
+        );
+    }
+    if wlt_client.get_gateway() != data.网关 {
+        log(format!(
+            "旧网关: {} 新网关: {}",
+            data.网关,
+            wlt_client.get_gateway()
+        ));
+        data.网关 = wlt_client.get_gateway().to_owned();
+    }
+    data.连续超时次数 = 0;
+    if !data.上次错误.is_empty() {
+        let mut error = std::mem::take(&mut data.上次错误);
+        if let Some(digest) = data.clear_errors() {
+            error = format!("{}\n{}", error, digest);
+        }
+        log("网络通任务恢复正常");
+        notify(config, data, &Event::Recovered { error });
+    }
+    // 网络通已经检测完成，DDNS更新失败时只记录并通知错误，下次检测时重试
+    if let Err(e) = update_ddns(config, data) {
+        notify_error(config, data, &e);
+    }
+    data.save()?;
+    // 网络恢复正常后补发之前发送失败的通知
+    if let Err(e) = outbox::flush(config, data, now) {
+        log(format!("读取发件箱失败: {:#}", e));
+    }
+
+    log_append(".");
+    Ok(())
+}
+
+/// 探测当前出口，失败时依次切换到首选出口及备用出口，直到探测成功；探测结果及选择的出口记录在data中，
+/// 首选出口exit探测失败时记录时间，failover为重试间隔内继续使用的备用出口
+fn probe_exits(
+    config: &Config,
+    data: &mut Data,
+    wlt_client: &mut WltClient,
+    exit: u8,
+    failover: Option<u8>,
+    exp: u32,
+    now: NaiveDateTime,
+) -> anyhow::Result<()> {
+    let timeout = Duration::from_millis(config.探测超时);
+    let mut candidates = vec![failover.unwrap_or(exit)];
+    for &backup in std::iter::once(&exit).chain(config.备用出口.iter()) {
+        if !candidates.contains(&backup) {
+            candidates.push(backup);
+        }
+    }
+
+    let previous = data.出口;
+    data.探测结果.clear();
+    let mut chosen = None;
+    for (i, &candidate) in candidates.iter().enumerate() {
+        if i > 0 {
+            log(format!(
+                "出口{}探测失败，切换到备用出口: {}",
+                candidates[i - 1],
+                candidate
+            ));
+            wlt_client.set_exit(candidate, exp);
+            wlt_client.set_wlt()?;
+            data.record_session(now, exp);
+        }
+        let results = probe_all(&config.探测目标, candidate, timeout);
+        let ok = results.iter().any(|result| result.成功);
+        data.探测结果.extend(results);
+        if candidate == exit {
+            data.不可用出口 = (!ok).then_some(exit);
+            data.不可用时间 = match ok {
+                true => String::new(),
+                false => now.format(TIME_FORMAT).to_string(),
+            };
+        }
+        if ok {
+            chosen = Some(candidate);
+            break;
+        }
+    }
+    data.出口 = chosen;
+    data.save()?;
+    match chosen {
+        Some(chosen) => {
+            // 和上次探测选择的出口比较，避免首选出口不可用时每次检测都通知
+            let old_exit = previous.unwrap_or(exit);
+            if chosen != old_exit {
+                let reason = match chosen == exit {
+                    true => "首选出口恢复".to_owned(),
+                    false => format!("出口{}探测失败", exit),
+                };
+                notify(
+                    config,
+                    data,
+                    &Event::ExitChanged {
+                        old_exit,
+                        new_exit: chosen,
+                        reason,
+                    },
+                );
+            }
+            Ok(())
+        }
+        None => {
+            let error = format!(
+                "所有出口的探测都失败\n{}",
+                data.探测结果
+                    .iter()
+                    .map(|result| format!("出口{} {}: {}", result.出口, result.目标, result.错误))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            match data.探测结果.iter().all(|result| result.超时) {
+                true => Err(WltError::AllTimedOut(error).into()),
+                false => Err(anyhow::anyhow!(error)),
+            }
+        }
+    }
+}
+
+/// 未登录时先登录，然后按照wlt_client的出口和使用时限开通网络，返回开通后控制页面上的状态
+pub fn switch_wlt(data: &mut Data, wlt_client: &mut WltClient) -> anyhow::Result<ControlPageInfo> {
+    let wlt_page = wlt_client.access_page()?;
+    if let WltPageType::LoginPage = wlt_page.page_type()? {
+        login_wlt(data, wlt_client, &wlt_page.search_ip()?)?;
+    }
+    let info = wlt_client.set_wlt()?.control_page_info()?;
+    data.record_session(Local::now().naive_local(), wlt_client.get_exp());
+    data.网关 = wlt_client.get_gateway().to_owned();
+    data.save()?;
+    log(format!("切换出口: {} {}", info.exit, info.exit_name));
+    Ok(info)
+}
+
+/// 网络通的当前状态，用于wlt_task status
+#[derive(Serialize, Debug, PartialEq)]
+pub struct WltStatus {
+    pub logged_in: bool,
+    pub ip: String,
+    pub gateway: String,
+    pub account: Option<String>,
+    pub exit: Option<u8>,
+    pub exit_name: Option<String>,
+    pub time_limit: Option<String>,
+    pub remaining: Option<String>,
+    pub expiry: Option<String>,
+}
+
+impl Display for WltStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "状态: {}",
+            if self.logged_in {
+                "已登录"
+            } else {
+                "未登录"
+            }
+        )?;
+        writeln!(f, "当前IP地址: {}", self.ip)?;
+        write!(f, "网关: {}", self.gateway)?;
+        let fields = [
+            ("网络通账号", self.account.clone()),
+            ("出口", self.exit.map(|exit| exit.to_string())),
+            ("出口名", self.exit_name.clone()),
+            ("使用时限", self.time_limit.clone()),
+            ("剩余时间", self.remaining.clone()),
+            ("到期时间", self.expiry.clone()),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                write!(f, "\n{}: {}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 只访问网络通页面读取当前状态，不登录也不修改出口
+pub fn query_status(wlt_client: &mut WltClient) -> anyhow::Result<WltStatus> {
+    let wlt_page = wlt_client.access_page()?;
+    let gateway = wlt_client.get_gateway().to_owned();
+    match wlt_page.page_type()? {
+        WltPageType::LoginPage => Ok(WltStatus {
+            logged_in: false,
+            ip: wlt_page.search_ip()?,
+            gateway,
+            account: None,
+            exit: None,
+            exit_name: None,
+            time_limit: None,
+            remaining: None,
+            expiry: None,
+        }),
+        WltPageType::ControlPage => {
+            let info = wlt_page.control_page_info()?;
+            Ok(WltStatus {
+                logged_in: true,
+                ip: info.ip,
+                gateway,
+                account: info.account,
+                exit: Some(info.exit),
+                exit_name: Some(info.exit_name),
+                time_limit: info.time_limit,
+                remaining: info.remaining,
+                expiry: info.expiry,
+            })
+        }
+    }
+}
+
+/// 断开网络通连接，并清除保存的rn
+pub fn logout_wlt(data: &mut Data, wlt_client: &mut WltClient) -> anyhow::Result<()> {
+    wlt_client.logout()?;
+    data.rn.clear();
+    data.save()?;
+    log("已退出网络通");
+    Ok(())
+}
+
+/// 访问网关、探测出口或更新DDNS超时，不是每次都通知
+pub fn is_timeout(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<WltError>(),
+        Some(WltError::Timeout(_) | WltError::AllTimedOut(_))
+    ) || is_request_timeout(e)
+}
+
+/// 记录错误并通知，错误信息中的密码会被替换为***；错误记录在data中，同样的错误在"错误通知间隔"内只通知一次，
+/// 之后仍在重复的错误汇总后通知，恢复正常时发送恢复通知
+pub fn notify_error(config: &Config, data: &mut Data, e: &anyhow::Error) {
+    let error = replace_password(e.to_string(), &config.网络通密码, "***");
+    log(&error);
+    let now = Local::now().naive_local();
+    let window = chrono::Duration::seconds(config.错误通知间隔 as i64);
+    // 登录失败时check_wlt已经发送了登录失败通知
+    let login_failed = matches!(
+        e.downcast_ref::<WltError>(),
+        Some(WltError::LoginFailed { .. })
+    );
+    let notices = match login_failed {
+        true => Vec::new(),
+        false => data.record_error(&error, now, window),
+    };
+    if notices.is_empty() && !login_failed {
+        log(format!(
+            "同样的错误在{}秒内已经通知过，不再通知",
+            config.错误通知间隔
+        ));
+    }
+    for error in notices {
+        notify(config, data, &Event::Error { error });
+    }
+    data.上次错误 = error;
+    if let Err(e) = data.save() {
+        log(format!("{:#}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::data::TIME_FORMAT;
+    use crate::ip_provider::{IpSource, IpSourceType};
+    use crate::mock_gateway::{start_wlt, GatewayState, MockServer, Response};
+    use crate::notify::{ChannelType, EventKind, NotifyChannel};
+    use crate::policy::ExitPolicy;
+
+    /// 接收通知的Webhook，返回收到的事件
+    fn start_notify_server() -> (MockServer, NotifyChannel) {
+        let server = MockServer::start("/", |_| Response::ok(""));
+        let channel = NotifyChannel {
+            类型: ChannelType::Webhook,
+            地址: server.url.clone(),
+            ..Default::default()
+        };
+        (server, channel)
+    }
+
+    fn notified_events(server: &MockServer) -> Vec<serde_json::Value> {
+        server
+            .requests()
+            .iter()
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect()
+    }
+
+    fn test_config(gateway: &str, state: &GatewayState) -> Config {
+        Config {
+            网络通用户名: state.name.clone(),
+            网络通密码: state.password.clone(),
+            网络通出口: 8,
+            网络通网关: vec![gateway.to_owned()],
+            检测IPv6: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_wlt_logs_in_and_records_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState::default();
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        assert_eq!(gateway_state.lock().unwrap().exit, 8);
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.ipv4, state.ip);
+        assert_eq!(data.rn, state.rn);
+        assert_eq!(data.网关, server.url);
+        assert_eq!(data.连续超时次数, 0);
+    }
+
+    #[test]
+    fn check_wlt_continues_after_ddns_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            通知渠道: vec![channel],
+            DDNS: vec![crate::ddns::DdnsRecord {
+                域名: "lab".to_owned(),
+                地址: crate::mock_gateway::closed_url(),
+                超时: 200,
+                ..Default::default()
+            }],
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.上次错误 = "连接网关超时".to_owned();
+        data.连续超时次数 = 3;
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.网关, server.url);
+        assert_eq!(data.连续超时次数, 0);
+        assert!(data.上次错误.starts_with("DDNS更新失败"));
+        assert!(data.域名记录.is_empty());
+        let events = notified_events(&notify_server);
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, ["ip_changed", "recovered", "error"]);
+    }
+
+    #[test]
+    fn check_wlt_keeps_matching_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, _) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].param("cmd"), None);
+    }
+
+    #[test]
+    fn check_wlt_switches_changed_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 0,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        assert_eq!(gateway_state.lock().unwrap().exit, 8);
+        assert_eq!(server.requests()[1].param("cmd").unwrap(), "set");
+    }
+
+    #[test]
+    fn check_wlt_notifies_exit_change_and_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 0,
+            ..Default::default()
+        };
+        let (server, _) = start_wlt(state.clone());
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            通知渠道: vec![channel],
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        data.ipv4 = state.ip.clone();
+        data.上次错误 = "连接失败".to_owned();
+        let failed_at = NaiveDateTime::parse_from_str("2024-07-01 08:00:00", TIME_FORMAT).unwrap();
+        for minutes in [0, 5, 10] {
+            data.record_error(
+                "连接失败",
+                failed_at + chrono::Duration::minutes(minutes),
+                chrono::Duration::hours(1),
+            );
+        }
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        let events = notified_events(&notify_server);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "exit_changed");
+        assert_eq!(
+            (&events[0]["old_exit"], &events[0]["new_exit"]),
+            (&0.into(), &8.into())
+        );
+        assert_eq!(events[1]["event"], "recovered");
+        assert_eq!(
+            events[1]["error"],
+            "连接失败\n以下错误重复出现，还没有通知:\n\
+             2024-07-01 08:00:00以来又出现2次，最后一次在2024-07-01 08:10:00: 连接失败"
+        );
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert!(data.上次错误.is_empty());
+        assert!(data.错误记录.is_empty());
+    }
+
+    #[test]
+    fn notify_error_records_last_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            网络通密码: "secret".to_owned(),
+            通知渠道: vec![channel],
+            ..Default::default()
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+
+        notify_error(&config, &mut data, &anyhow::anyhow!("密码secret错误"));
+
+        let events = notified_events(&notify_server);
+        assert_eq!(events[0]["event"], "error");
+        assert_eq!(events[0]["error"], "密码***错误");
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.上次错误, "密码***错误");
+    }
+
+    #[test]
+    fn notify_error_suppresses_repeats() {
+        let dir = tempfile::tempdir().unwrap();
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            通知渠道: vec![channel],
+            ..Default::default()
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+
+        notify_error(
+            &config,
+            &mut data,
+            &anyhow::anyhow!("连接127.0.0.1:8001失败"),
+        );
+        notify_error(
+            &config,
+            &mut data,
+            &anyhow::anyhow!("连接127.0.0.1:8002失败"),
+        );
+        notify_error(&config, &mut data, &anyhow::anyhow!("页面有误"));
+
+        let events = notified_events(&notify_server);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["error"], "连接127.0.0.1:8001失败");
+        assert_eq!(events[1]["error"], "页面有误");
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.错误记录.len(), 2);
+        assert_eq!(data.错误记录[0].次数, 2);
+        assert_eq!(data.错误记录[0].未通知次数, 1);
+        assert_eq!(data.错误记录[0].错误, "连接127.0.0.1:8002失败");
+        assert_eq!(data.上次错误, "页面有误");
+    }
+
+    #[test]
+    fn record_error_sends_digest_after_window() {
+        let mut data = Data::default();
+        let window = chrono::Duration::hours(1);
+        let start = NaiveDateTime::parse_from_str("2024-07-01 00:00:00", TIME_FORMAT).unwrap();
+        let at = |minutes| start + chrono::Duration::minutes(minutes);
+
+        assert_eq!(data.record_error("超时", at(0), window), ["超时"]);
+        assert!(data.record_error("超时", at(5), window).is_empty());
+        assert!(data.record_error("超时", at(30), window).is_empty());
+        assert_eq!(
+            data.record_error("超时", at(60), window),
+            ["以下错误重复出现（同样的错误3600秒内只通知一次）:\n\
+              2024-07-01 00:00:00以来又出现3次，最后一次在2024-07-01 01:00:00: 超时"]
+        );
+        assert!(data.record_error("超时", at(90), window).is_empty());
+        // 一直没有再出现的错误在间隔之后出现时单独通知
+        assert_eq!(data.record_error("密码错误", at(90), window), ["密码错误"]);
+        assert!(data.record_error("密码错误", at(100), window).is_empty());
+        let mut data = Data::default();
+        data.record_error("超时", at(0), window);
+        assert_eq!(data.record_error("超时", at(120), window), ["超时"]);
+        assert_eq!(data.错误记录[0].次数, 2);
+        // 间隔为0时每次都通知
+        assert_eq!(
+            data.record_error("超时", at(121), chrono::Duration::zero()),
+            ["超时"]
+        );
+    }
+
+    #[test]
+    fn switch_wlt_logs_in_and_sets_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState::default();
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = Config {
+            网络通出口: 0,
+            网络通使用时限: 14400,
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        let info = switch_wlt(&mut data, &mut wlt_client).unwrap();
+
+        assert_eq!(info.exit, 0);
+        assert_eq!(info.time_limit.as_deref(), Some("4小时"));
+        assert_eq!(info.message.as_deref(), Some("网络设置成功"));
+        let gateway_state = gateway_state.lock().unwrap();
+        assert_eq!((gateway_state.exit, gateway_state.exp), (0, 14400));
+        assert_eq!(data.rn, state.rn);
+    }
+
+    #[test]
+    fn switch_wlt_skips_login_when_logged_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = Config {
+            网络通出口: 0,
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        switch_wlt(&mut data, &mut wlt_client).unwrap();
+
+        assert_eq!(gateway_state.lock().unwrap().exit, 0);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].param("cmd").unwrap(), "set");
+    }
+
+    #[test]
+    fn query_status_reports_logged_out() {
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let data = Data::default();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        let status = query_status(&mut wlt_client).unwrap();
+
+        assert!(!status.logged_in);
+        assert_eq!(status.ip, state.ip);
+        assert_eq!(status.exit, None);
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["logged_in"], false);
+        assert_eq!(json["exit"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn query_status_reports_session_without_changing_it() {
+        let state = GatewayState {
+            logged_in: true,
+            exit: 1,
+            exp: 14400,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let mut data = Data::default();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        let status = query_status(&mut wlt_client).unwrap();
+
+        assert_eq!(
+            status,
+            WltStatus {
+                logged_in: true,
+                ip: state.ip.clone(),
+                gateway: server.url.clone(),
+                account: Some(state.name.clone()),
+                exit: Some(1),
+                exit_name: Some("电信网出口".to_owned()),
+                time_limit: Some("4小时".to_owned()),
+                remaining: Some("4小时".to_owned()),
+                expiry: None,
+            }
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(gateway_state.lock().unwrap().exit, 1);
+    }
+
+    #[test]
+    fn logout_wlt_clears_rn() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        logout_wlt(&mut data, &mut wlt_client).unwrap();
+
+        assert!(!gateway_state.lock().unwrap().logged_in);
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert!(data.rn.is_empty());
+    }
+
+    #[test]
+    fn time_until_renewal_counts_down_to_advance() {
+        let now = Local::now().naive_local();
+        let mut data = Data::default();
+        assert_eq!(time_until_renewal(&data, 600, now), None);
+
+        data.record_session(now, 3600);
+        let wait = time_until_renewal(&data, 600, now).unwrap();
+        assert!(wait > Duration::from_secs(2990) && wait <= Duration::from_secs(3000));
+        let later = now + chrono::Duration::seconds(3100);
+        assert_eq!(time_until_renewal(&data, 600, later), Some(Duration::ZERO));
+
+        data.record_session(now, 0);
+        assert!(data.到期时间.is_empty());
+        assert_eq!(time_until_renewal(&data, 600, now), None);
+    }
+
+    #[test]
+    fn check_wlt_records_session_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let config = Config {
+            网络通使用时限: 14400,
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let opened = NaiveDateTime::parse_from_str(&data.开通时间, "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(
+            data.session_expiry().unwrap() - opened,
+            chrono::Duration::hours(4)
+        );
+    }
+
+    #[test]
+    fn check_wlt_renews_before_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            exp: 3600,
+            ..Default::default()
+        };
+        let (server, _) = start_wlt(state.clone());
+        let config = Config {
+            网络通使用时限: 3600,
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        // 还有1小时到期，不需要续期
+        data.record_session(Local::now().naive_local(), 3600);
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(server.requests().len(), 1);
+
+        // 还有5分钟到期，提前续期
+        let opened = Local::now().naive_local() - chrono::Duration::seconds(3300);
+        data.record_session(opened, 3600);
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].param("cmd").unwrap(), "set");
+        let wait = time_until_renewal(&data, 600, Local::now().naive_local()).unwrap();
+        assert!(wait > Duration::from_secs(2900));
+    }
+
+    #[test]
+    fn check_wlt_reads_expiry_from_control_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            exp: 3600,
+            ..Default::default()
+        };
+        let (server, _) = start_wlt(state.clone());
+        let config = Config {
+            网络通使用时限: 3600,
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        let wait = time_until_renewal(&data, 600, Local::now().naive_local()).unwrap();
+        assert!(wait > Duration::from_secs(2900) && wait <= Duration::from_secs(3000));
+    }
+
+    #[test]
+    fn check_wlt_renews_when_time_limit_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            exp: 14400,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = test_config(&server.url, &state);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        data.record_session(Local::now().naive_local(), 14400);
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        // 出口相同，使用时限从4小时变为永久
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].param("cmd").unwrap(), "set");
+        assert_eq!(gateway_state.lock().unwrap().exp, 0);
+        assert!(data.到期时间.is_empty());
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn check_wlt_follows_active_exit_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let config = Config {
+            出口策略: vec![ExitPolicy {
+                出口: 0,
+                使用时限: 3600,
+                ..Default::default()
+            }],
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        let gateway_state = gateway_state.lock().unwrap();
+        assert_eq!((gateway_state.exit, gateway_state.exp), (0, 3600));
+    }
+
+    #[test]
+    fn check_wlt_requires_ip_sources_to_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, _) = start_wlt(state.clone());
+        let echo_ip = Arc::new(Mutex::new("10.0.0.1".to_owned()));
+        let echo = {
+            let echo_ip = echo_ip.clone();
+            MockServer::start("/", move |_| Response::ok(echo_ip.lock().unwrap().clone()))
+        };
+        let config = Config {
+            IP来源: vec![
+                IpSource::default(),
+                IpSource {
+                    类型: IpSourceType::Http,
+                    地址: echo.url.clone(),
+                    ..Default::default()
+                },
+            ],
+            IP一致数量: 2,
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        data.ipv4 = "114.214.180.1".to_owned();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(data.ipv4, "114.214.180.1");
+
+        *echo_ip.lock().unwrap() = state.ip.clone();
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(data.ipv4, state.ip);
+    }
+
+    /// 只有在模拟网关的出口为reachable_exit时才能及时响应的探测目标
+    fn start_probe_target(
+        gateway_state: Arc<Mutex<GatewayState>>,
+        reachable_exit: u8,
+    ) -> MockServer {
+        MockServer::start("/", move |_| {
+            if gateway_state.lock().unwrap().exit == reachable_exit {
+                Response::ok("")
+            } else {
+                Response::ok("").delay(Duration::from_secs(2))
+            }
+        })
+    }
+
+    fn probe_config(gateway: &str, state: &GatewayState, target: &str) -> Config {
+        Config {
+            探测目标: vec![target.to_owned()],
+            探测超时: 300,
+            备用出口: vec![1, 0],
+            ..test_config(gateway, state)
+        }
+    }
+
+    #[test]
+    fn check_wlt_keeps_exit_when_probe_succeeds() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let target = start_probe_target(gateway_state.clone(), 8);
+        let config = probe_config(&server.url, &state, &target.url);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        assert_eq!(server.requests().len(), 1);
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.出口, Some(8));
+        assert_eq!(data.探测结果.len(), 1);
+        assert!(data.探测结果[0].成功);
+    }
+
+    #[test]
+    fn check_wlt_fails_over_to_backup_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let target = start_probe_target(gateway_state.clone(), 0);
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            通知渠道: vec![NotifyChannel {
+                事件: vec![EventKind::ExitChanged],
+                ..channel
+            }],
+            ..probe_config(&server.url, &state, &target.url)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+
+        assert_eq!(gateway_state.lock().unwrap().exit, 0);
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.出口, Some(0));
+        let tried: Vec<(u8, bool)> = data.探测结果.iter().map(|r| (r.出口, r.成功)).collect();
+        assert_eq!(tried, [(8, false), (1, false), (0, true)]);
+        let events = notified_events(&notify_server);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "exit_changed");
+        assert_eq!(
+            events[0]["message"],
+            "旧出口: 8 新出口: 0 原因: 出口8探测失败"
+        );
+
+        // 首选出口仍然不可用时，切回备用出口不再通知
+        let mut data = data;
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(data.出口, Some(0));
+        assert_eq!(notified_events(&notify_server).len(), 1);
+    }
+
+    #[test]
+    fn check_wlt_keeps_backup_exit_until_retry_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let target = start_probe_target(gateway_state.clone(), 0);
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            通知渠道: vec![NotifyChannel {
+                事件: vec![EventKind::ExitChanged],
+                ..channel
+            }],
+            ..probe_config(&server.url, &state, &target.url)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+        let set_calls = || {
+            server
+                .requests()
+                .iter()
+                .filter(|request| request.param("cmd").as_deref() == Some("set"))
+                .count()
+        };
+
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(data.出口, Some(0));
+        assert_eq!(data.不可用出口, Some(8));
+        assert_eq!(set_calls(), 2);
+
+        // 首选出口仍然不可用，重试间隔内不再切回首选出口
+        for _ in 0..2 {
+            check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+            assert_eq!(set_calls(), 2);
+            assert_eq!(gateway_state.lock().unwrap().exit, 0);
+            let tried: Vec<(u8, bool)> = data.探测结果.iter().map(|r| (r.出口, r.成功)).collect();
+            assert_eq!(tried, [(0, true)]);
+        }
+
+        // 重试间隔内出口被改为其他出口时，改回的是备用出口
+        gateway_state.lock().unwrap().exit = 1;
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(set_calls(), 3);
+        assert_eq!(gateway_state.lock().unwrap().exit, 0);
+        let events = notified_events(&notify_server);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1]["message"],
+            "旧出口: 1 新出口: 0 原因: 与网络通出口的设置不同"
+        );
+
+        // 超过重试间隔后再尝试首选出口，仍然失败时切回备用出口，不通知
+        data.不可用时间 = "2000-01-01 00:00:00".to_owned();
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(set_calls(), 6);
+        assert_eq!(data.出口, Some(0));
+        assert_ne!(data.不可用时间, "2000-01-01 00:00:00");
+        assert_eq!(notified_events(&notify_server).len(), 2);
+
+        // 首选出口恢复后切回，并清空不可用出口
+        data.不可用时间 = "2000-01-01 00:00:00".to_owned();
+        let target = start_probe_target(gateway_state.clone(), 8);
+        let config = Config {
+            探测目标: vec![target.url.clone()],
+            ..config
+        };
+        check_wlt(&config, &mut data, &mut wlt_client).unwrap();
+        assert_eq!(data.出口, Some(8));
+        assert_eq!(data.不可用出口, None);
+        assert!(data.不可用时间.is_empty());
+        let events = notified_events(&notify_server);
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[2]["message"],
+            "旧出口: 0 新出口: 8 原因: 首选出口恢复"
+        );
+    }
+
+    #[test]
+    fn check_wlt_reports_all_exits_unreachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState {
+            logged_in: true,
+            exit: 8,
+            ..Default::default()
+        };
+        let (server, gateway_state) = start_wlt(state.clone());
+        let target = start_probe_target(gateway_state.clone(), 5);
+        let config = probe_config(&server.url, &state, &target.url);
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.rn = state.rn.clone();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        let e = check_wlt(&config, &mut data, &mut wlt_client)
+            .err()
+            .unwrap();
+        assert!(e.to_string().starts_with("所有出口的探测都失败"));
+        assert!(is_timeout(&e));
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.出口, None);
+        assert_eq!(data.探测结果.len(), 3);
+    }
+
+    #[test]
+    fn check_wlt_reports_login_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let (notify_server, channel) = start_notify_server();
+        let config = Config {
+            网络通密码: "wrong".to_owned(),
+            通知渠道: vec![NotifyChannel {
+                事件: vec![EventKind::LoginFailed],
+                ..channel
+            }],
+            ..test_config(&server.url, &state)
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        let e = check_wlt(&config, &mut data, &mut wlt_client)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "用户名或密码错误");
+        assert!(data.ipv4.is_empty());
+        let events = notified_events(&notify_server);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "login_failed");
+
+        // 登录失败不再发送出错通知，但恢复时仍然发送恢复通知
+        let config = Config {
+            通知渠道: vec![NotifyChannel {
+                事件: vec![EventKind::LoginFailed, EventKind::Error],
+                ..config.通知渠道[0].clone()
+            }],
+            ..config
+        };
+        notify_error(&config, &mut data, &e);
+        assert_eq!(notified_events(&notify_server).len(), 1);
+        assert_eq!(data.上次错误, "用户名或密码错误");
+        assert!(data.错误记录.is_empty());
+    }
+
+    #[test]
+    fn check_wlt_reports_unknown_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start("/cgi-bin/ip", |_| Response::ok("<html></html>"));
+        let config = test_config(&server.url, &GatewayState::default());
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+
+        let e = check_wlt(&config, &mut data, &mut wlt_client)
+            .err()
+            .unwrap();
+        assert!(e.to_string().starts_with("未知类型页面"));
+    }
+
+    #[test]
+    fn check_wlt_reports_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start("/cgi-bin/ip", |_| {
+            Response::ok("").delay(Duration::from_secs(2))
+        });
+        let config = test_config(&server.url, &GatewayState::default());
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let mut wlt_client = new_wlt_client(&config, &data).unwrap();
+        wlt_client.set_timeout(Duration::from_millis(200)).unwrap();
+
+        let e = check_wlt(&config, &mut data, &mut wlt_client)
+            .err()
+            .unwrap();
+        assert!(is_timeout(&e));
+    }
+
+    #[test]
+    fn ddns_and_probe_timeouts_are_timeouts() {
+        let server = MockServer::start("/", |_| Response::ok("OK").delay(Duration::from_secs(2)));
+        let config = Config {
+            DDNS: vec![crate::ddns::DdnsRecord {
+                域名: "lab".to_owned(),
+                地址: format!("{}?token={{密钥}}", server.url),
+                密钥: "topsecret".to_owned(),
+                超时: 200,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut data = Data::default();
+        data.ipv4 = "114.214.180.23".to_owned();
+        let e = update_ddns(&config, &mut data).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<WltError>(),
+            Some(WltError::AllTimedOut(_))
+        ));
+        assert!(is_timeout(&e));
+        assert!(!e.to_string().contains("topsecret"));
+
+        // 有一条记录不是超时时不算超时
+        let mut config = config;
+        config.DDNS.push(crate::ddns::DdnsRecord {
+            域名: "lab2".to_owned(),
+            地址: crate::mock_gateway::closed_url(),
+            超时: 200,
+            ..Default::default()
+        });
+        let e = update_ddns(&config, &mut data).unwrap_err();
+        assert!(!is_timeout(&e));
+
+        let e = anyhow::Error::from(WltError::UnknownPage("未知类型页面".to_owned()));
+        assert!(!is_timeout(&e));
+    }
+}
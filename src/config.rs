@@ -1,6 +1,18 @@
+use std::{borrow::Cow, collections::BTreeMap};
+
 use serde::{Deserialize, Serialize};
 
+use crate::ddns::DdnsRecord;
+use crate::email::SmtpEncryption;
+use crate::error::WltError;
+use crate::ip_provider::{IpFamily, IpSource};
+use crate::ipv6::parse_prefix;
+use crate::notify::NotifyChannel;
+use crate::policy::ExitPolicy;
+use crate::template::{self, MessageTemplate};
 use crate::utils::{str_decrypt, substr_encrypt};
+use crate::webhook::Webhook;
+use crate::wlt::DEFAULT_WLT_URL;
 
 const CONFIG_PATH: &str = "config.toml";
 const CONFIG_COMMENT: &str = r#"
@@ -16,6 +28,7 @@ const CONFIG_COMMENT: &str = r#"
 #   6 联通网出口3(国际,默认联通,其他分流)
 #   7 教育网出口2(国际,默认教育网,其他分流)
 #   8 移动网出口(国际,无P2P或带宽限制)
+# 网络通网关：网络通页面的地址，可以填多个，依次尝试，如["http://202.38.64.59/cgi-bin/ip", "https://wlt.ustc.edu.cn/cgi-bin/ip"]
 # 网络通使用时限：
 #   0     永久
 #   3600  1小时
@@ -23,26 +36,217 @@ const CONFIG_COMMENT: &str = r#"
 #   39600 11小时
 #   50400 14小时
 # 邮箱服务器：如smtp.qq.com
-# 邮箱用户名：如10000@qq.com
+# 邮箱端口：SMTP服务器端口，为0时按照邮箱加密选择默认端口（TLS为465，STARTTLS为587，无为25）
+# 邮箱加密：TLS（直接建立TLS连接）、STARTTLS（先建立明文连接再升级为TLS，服务器不支持时不发送）或无（不加密，只适合内网的中继服务器）
+# 邮箱用户名：如10000@qq.com，留空则不认证，适合不需要登录的中继服务器
 # 邮箱密码：一般是SMTP授权码，如f0123456789abcdef，运行程序后，这个密码会被加密
+# 发件人名称：邮件中显示的发件人名字，如"实验室网络通"，留空则只显示地址
+# 发件人地址：留空则使用邮箱用户名
 # 邮件发送列表：可以填自己的邮箱，如["10000@qq.com", "10000@mail.ustc.edu.cn"]，留空则禁用邮件功能
 # 邮件主题：也即邮件标题
-# 邮件内容：其中的{新IPv4}等会被替换为相应的IP地址
+# 邮件内容：IP变化时通知的内容，是minijinja（与Jinja2相同）模板，{{ 新IPv4 }}等会被替换为相应的值，
+#   也可以使用{% if %}、{% for %}等语句，以前的{新IPv4}形式会被自动改为{{ 新IPv4 }}，邮件主题也是模板，可以使用的变量有：
+#   事件（IP变化、出错、恢复、出口变化、登录失败）、事件ID（ip_changed等）、主机名、机器名、网络通用户名、时间、
+#   旧IPv4、新IPv4、旧IPv6、新IPv6、出口、出口名、旧出口、新出口、原因（出口变化的原因）、错误（出错、恢复、登录失败时的错误信息）、
+#   历史（最近10次IP变化，每项有时间、IPv4、IPv6），例如：
+#   {% for h in 历史 %}{{ h.时间 }} {{ h.IPv4 }}
+#   {% endfor %}
+#   模板中有语法错误或未知的变量时，程序会报错
+# 机器名：通知模板中的机器名，如"实验室A"，留空则使用主机名
+# Webhook：IP地址变化或出错时POST一个JSON，可以填多个，例如：
+#   [["Webhook"]]
+#   "地址" = "https://example.com/hook"
+#   "请求头" = ["Authorization: Bearer xxx"]
+#   "签名密钥" = "xxx"
+#   签名密钥不为空时，请求头X-Wlt-Signature为"sha256="加上请求体的HMAC-SHA256，运行程序后签名密钥会被加密；
+#   连接失败、超时或服务器返回5xx、429时最多重试"重试次数"次，第一次等待"重试间隔"毫秒，之后每次翻倍
+# 通知渠道：留空时用邮件和上面的Webhook通知IP变化和出错；设置后只通过这些渠道通知，可以填多个，每项包括：
+#   类型：邮件（使用上面的邮箱设置）、Webhook、Telegram、Server酱、钉钉、飞书、命令
+#   事件：订阅的事件，可以是IP变化、出错、恢复、出口变化、登录失败，留空则订阅所有事件
+#   地址：Webhook、钉钉、飞书的机器人地址，Telegram、Server酱的接口地址（留空则使用官方地址）
+#   令牌：Telegram的机器人令牌或Server酱的SendKey，运行程序后会被加密
+#   聊天ID：Telegram的聊天ID
+#   签名密钥：Webhook、钉钉、飞书的签名密钥，留空则不签名，运行程序后会被加密
+#   请求头：发送HTTP请求时额外的请求头
+#   命令、参数：类型为命令时运行的程序及参数，事件的内容通过WLT_EVENT、WLT_TITLE、WLT_MESSAGE等环境变量传递
+#   重试次数、重试间隔、超时：同Webhook，类型为命令时超时为命令的运行时间限制
+#   例如IP变化时通知Telegram，出错时运行脚本：
+#   [["通知渠道"]]
+#   "类型" = "Telegram"
+#   "事件" = ["IP变化"]
+#   "令牌" = "123456:ABC-DEF"
+#   "聊天ID" = "123456789"
+#   [["通知渠道"]]
+#   "类型" = "命令"
+#   "事件" = ["出错", "恢复"]
+#   "命令" = "/home/user/notify.sh"
+# 通知模板：代替某个事件默认的主题和内容，同一个事件只使用第一个模板，每项包括：
+#   事件：同通知渠道中的事件
+#   主题、内容：模板，留空则使用默认的主题和内容（IP变化时为邮件主题和邮件内容）
+#   HTML内容：模板，不为空时邮件同时包含纯文本和HTML两种内容，变量中的HTML字符会被转义
+#   例如出错时：
+#   [["通知模板"]]
+#   "事件" = "出错"
+#   "主题" = "{{ 机器名 }}的网络通任务出错"
+#   "内容" = "{{ 时间 }} {{ 错误 }}"
+# 补发间隔：通知发送失败时保存在outbox.toml中，之后的检测成功时补发，第一次失败后等待这么多秒，之后每次翻倍
+# 最大补发间隔：补发的等待时间最多为这么多秒，通知发送成功后才从outbox.toml中删除
+# 补发期限：通知第一次发送失败后超过这么多秒仍未补发成功时，从outbox.toml中删除，不再补发
+# 错误通知间隔：同样的错误（只有数字不同的错误也算同样的）在这么多秒内只通知一次，超过这个时间后仍在重复的错误汇总成一条通知，
+#   检测恢复正常时发送一条恢复通知，为0时每次出错都通知
+# 检测IPv6：是否检测IPv6地址的变化
+# IPv6网卡：从这些网卡上选择IPv6地址，如["eth0"]，留空则考虑所有网卡
+# IPv6前缀：只选择这些前缀中的地址，如["2001:da8:d800::/48"]，留空则选择任意全球单播地址
+# IPv6优先稳定地址：有多个地址时，优先选择稳定地址而不是隐私扩展生成的临时地址（仅Linux下可以区分）
+# IPv6查询地址：本机网卡上没有找到IPv6地址时，依次通过这些返回IP地址的网页查询，如["http://api6.ipify.org/"]，留空则不查询
+# IPv6查询超时：通过网页查询IPv6地址的超时时间，单位为毫秒
+# IP来源：检测IP地址的来源，留空时IPv4来自网络通页面，IPv6按照上面的IPv6设置检测；
+#   设置了某个协议的来源后，这个协议的地址只从这些来源检测
+#   类型：网络通（网络通页面，只有IPv4）、网卡（本机网卡）、网页（返回纯文本IP地址的网页）、DNS（向DNS服务器查询本机地址）
+#   协议：IPv4或IPv6
+#   地址：类型为网页时是网页地址，类型为DNS时是DNS服务器地址，如"208.67.222.222:53"，查询IPv6时需要使用IPv6地址
+#   域名：类型为DNS时查询的域名，默认为"myip.opendns.com"
+#   超时：单位为毫秒
+#   例如同时使用网络通页面、网页和DNS检测IPv4：
+#   [["IP来源"]]
+#   "类型" = "网络通"
+#   [["IP来源"]]
+#   "类型" = "网页"
+#   "地址" = "http://api.ipify.org/"
+#   [["IP来源"]]
+#   "类型" = "DNS"
+#   "地址" = "208.67.222.222:53"
+# IP一致数量：至少这么多个来源得到相同的地址时才认为检测成功，否则保留之前的地址，避免某个来源出错时误报IP变化
+# DDNS：IP地址变化时更新的域名记录，更新失败时下次检测会重试
+#   类型：RFC2136（向DNS服务器发送动态更新）或HTTP（访问服务商的更新接口）
+#   域名：要更新的域名，如"lab.example.com"
+#   协议：IPv4时更新A记录，IPv6时更新AAAA记录
+#   地址：类型为RFC2136时是DNS服务器地址，如"ns1.example.com:53"；类型为HTTP时是请求地址
+#   区域、TTL、密钥名：类型为RFC2136时使用，密钥名为空时不签名，否则使用hmac-sha256的TSIG签名
+#   密钥：类型为RFC2136时是Base64编码的TSIG密钥，类型为HTTP时是服务商的密码或令牌，运行程序后会被加密
+#   用户名：类型为HTTP时不为空则使用Basic认证，密码为密钥
+#   方法、请求头、请求体：类型为HTTP时使用，其中的{IP}、{旧IP}、{域名}、{用户名}、{密钥}会被替换
+#   地址中替换的值会经过URL编码，出错时日志和通知中不包含地址
+#   成功标志：类型为HTTP时，响应中包含这个字符串才认为更新成功，留空则只检查状态码
+#   超时：单位为毫秒
+#   例如更新DuckDNS：
+#   [["DDNS"]]
+#   "类型" = "HTTP"
+#   "域名" = "mylab"
+#   "地址" = "https://www.duckdns.org/update?domains={域名}&token={密钥}&ip={IP}"
+#   "密钥" = "duckdns的token"
+#   "成功标志" = "OK"
+# 检测间隔：wlt_task daemon每隔多少秒检测一次
+# 最大检测间隔：wlt_task daemon连续超时时，检测间隔会翻倍，但不超过这个秒数
+# 监听网络变化：wlt_task daemon在网络变化（获得新地址、网卡连接）时立即检测一次（仅Linux可用）
+# 续期提前时间：网络通使用时限不是永久时，在到期前多少秒重新开通网络，应大于计划任务的间隔（300秒）
+# 探测目标：开通网络后用来检测出口是否可用的地址，如["https://www.baidu.com", "tcp://223.5.5.5:53"]，
+#   http(s)://开头的发送HEAD请求，tcp://开头的建立TCP连接，任意一个成功即认为出口可用，留空则不探测
+# 探测超时：每个探测目标的超时时间，单位为毫秒
+# 备用出口：探测失败时依次尝试的出口，如[1, 0]，首选出口恢复后会自动切回
+# 首选出口重试间隔：首选出口探测失败、切换到备用出口后，在这么多秒内继续使用备用出口，之后再尝试首选出口
+# 出口策略：在指定时间段内代替网络通出口和网络通使用时限，按顺序使用第一条生效的策略，都不生效时使用网络通出口
+#   星期：1为星期一，7为星期日，留空表示每天
+#   开始、结束：如"08:00"，结束早于开始表示跨越午夜，结束等于开始表示全天
+#   例如工作日白天使用教育网出口，其余时间使用移动网出口：
+#   [["出口策略"]]
+#   "星期" = [1, 2, 3, 4, 5]
+#   "开始" = "08:00"
+#   "结束" = "18:00"
+#   "出口" = 0
+#   "使用时限" = 0
+# 默认profile：没有用--profile指定时使用的profile，留空则不使用profile
+# profile：多个网络通账号共用一台电脑时，每人一个profile，其中的设置会覆盖外层的同名设置，没有设置的项使用外层的设置，
+#   可以设置网络通用户名、网络通密码、网络通出口、网络通使用时限、邮箱服务器、邮箱端口、邮箱加密、邮箱用户名、邮箱密码、发件人名称、发件人地址、
+#   邮件发送列表、邮件主题、邮件内容，
+#   profile的名字只能包含英文字母、数字、-和_，每个profile的数据分别保存在data.<profile>.toml中，例如：
+#   [profile.alice]
+#   "网络通用户名" = "alice"
+#   "网络通密码" = "password"
+#   "邮件发送列表" = ["alice@mail.ustc.edu.cn"]
 "#;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// profile中设置的项会覆盖外层的同名设置
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 网络通用户名: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 网络通密码: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 网络通出口: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 网络通使用时限: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮箱服务器: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮箱端口: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮箱加密: Option<SmtpEncryption>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮箱用户名: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮箱密码: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 发件人名称: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 发件人地址: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮件发送列表: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮件主题: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 邮件内容: Option<String>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Config {
     pub 网络通用户名: String,
     pub 网络通密码: String,
     pub 网络通出口: u8,
     pub 网络通使用时限: u32,
+    pub 网络通网关: Vec<String>,
     pub 邮箱服务器: String,
+    pub 邮箱端口: u16,
+    pub 邮箱加密: SmtpEncryption,
     pub 邮箱用户名: String,
     pub 邮箱密码: String,
+    pub 发件人名称: String,
+    pub 发件人地址: String,
     pub 邮件发送列表: Vec<String>,
     pub 邮件主题: String,
     pub 邮件内容: String,
+    pub 机器名: String,
+    pub Webhook: Vec<Webhook>,
+    pub 通知渠道: Vec<NotifyChannel>,
+    pub 通知模板: Vec<MessageTemplate>,
+    pub 补发间隔: u64,
+    pub 最大补发间隔: u64,
+    pub 补发期限: u64,
+    pub 错误通知间隔: u64,
     pub 检测IPv6: bool,
+    pub IPv6网卡: Vec<String>,
+    pub IPv6前缀: Vec<String>,
+    pub IPv6优先稳定地址: bool,
+    pub IPv6查询地址: Vec<String>,
+    pub IPv6查询超时: u64,
+    pub IP来源: Vec<IpSource>,
+    pub IP一致数量: usize,
+    pub DDNS: Vec<DdnsRecord>,
+    pub 检测间隔: u64,
+    pub 最大检测间隔: u64,
+    pub 监听网络变化: bool,
+    pub 续期提前时间: u64,
+    pub 探测目标: Vec<String>,
+    pub 探测超时: u64,
+    pub 备用出口: Vec<u8>,
+    pub 首选出口重试间隔: u64,
+    pub 出口策略: Vec<ExitPolicy>,
+    pub 默认profile: String,
+    pub profile: BTreeMap<String, Profile>,
 }
 
 impl Default for Config {
@@ -52,34 +256,118 @@ impl Default for Config {
             网络通密码: String::new(),
             网络通出口: 8,
             网络通使用时限: 0,
+            网络通网关: vec![DEFAULT_WLT_URL.to_string()],
             邮箱服务器: "smtp.qq.com".to_string(),
+            邮箱端口: 0,
+            邮箱加密: SmtpEncryption::Tls,
             邮箱用户名: "10000@qq.com".to_string(),
             邮箱密码: "f0123456789abcdef".to_string(),
+            发件人名称: String::new(),
+            发件人地址: String::new(),
             邮件发送列表: Vec::new(),
             邮件主题: "网络通IP变化通知".to_string(),
             邮件内容: "\
-旧IPv4: {旧IPv4}
-旧IPv6: {旧IPv6}
-新IPv4: {新IPv4}
-新IPv6: {新IPv6}
+旧IPv4: {{ 旧IPv4 }}
+旧IPv6: {{ 旧IPv6 }}
+新IPv4: {{ 新IPv4 }}
+新IPv6: {{ 新IPv6 }}
 "
             .to_string(),
+            机器名: String::new(),
+            Webhook: Vec::new(),
+            通知渠道: Vec::new(),
+            通知模板: Vec::new(),
+            补发间隔: 60,
+            最大补发间隔: 3600,
+            补发期限: 86400,
+            错误通知间隔: 3600,
             检测IPv6: true,
+            IPv6网卡: Vec::new(),
+            IPv6前缀: Vec::new(),
+            IPv6优先稳定地址: true,
+            IPv6查询地址: Vec::new(),
+            IPv6查询超时: 5000,
+            IP来源: Vec::new(),
+            IP一致数量: 1,
+            DDNS: Vec::new(),
+            检测间隔: 300,
+            最大检测间隔: 1800,
+            监听网络变化: true,
+            续期提前时间: 600,
+            探测目标: Vec::new(),
+            探测超时: 3000,
+            备用出口: Vec::new(),
+            首选出口重试间隔: 1800,
+            出口策略: Vec::new(),
+            默认profile: String::new(),
+            profile: BTreeMap::new(),
+        }
+    }
+}
+
+/// 解密密码，返回是否有明文密码需要保存以加密
+fn decrypt_password(password: &mut String) -> bool {
+    if let Ok(plain_password) = str_decrypt(&password) {
+        *password = plain_password;
+        false
+    } else {
+        !password.is_empty()
+    }
+}
+
+/// 把模板中以前的{新IPv4}形式的占位符改为新语法，返回是否需要保存
+fn migrate_template(template: &mut String) -> bool {
+    match template::migrate(template) {
+        Cow::Owned(migrated) => {
+            *template = migrated;
+            true
         }
+        Cow::Borrowed(_) => false,
     }
 }
 
+fn no_profile(name: &str) -> WltError {
+    WltError::Config(anyhow::anyhow!("config.toml中没有名为{}的profile", name))
+}
+
+fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 impl Config {
+    fn passwords(&self) -> Vec<&str> {
+        let mut passwords = vec![self.网络通密码.as_str(), self.邮箱密码.as_str()];
+        passwords.extend(self.DDNS.iter().map(|record| record.密钥.as_str()));
+        passwords.extend(self.Webhook.iter().map(|webhook| webhook.签名密钥.as_str()));
+        for channel in self.通知渠道.iter() {
+            passwords.extend([channel.令牌.as_str(), channel.签名密钥.as_str()]);
+        }
+        for profile in self.profile.values() {
+            passwords.extend(profile.网络通密码.as_deref());
+            passwords.extend(profile.邮箱密码.as_deref());
+        }
+        passwords
+    }
+
     fn save(&self) -> anyhow::Result<()> {
-        let config_string = toml::to_string_pretty(self)?;
-        let config_string = substr_encrypt(config_string, &self.网络通密码)?;
-        let config_string = substr_encrypt(config_string, &self.邮箱密码)?;
+        let mut config_string = toml::to_string_pretty(self)?;
+        for password in self.passwords() {
+            config_string = substr_encrypt(config_string, password)?;
+        }
         let content = format!("{}\n{}", config_string, CONFIG_COMMENT);
         std::fs::write(CONFIG_PATH, content)?;
         Ok(())
     }
 
-    pub fn load() -> anyhow::Result<Self> {
+    pub fn load() -> Result<Self, WltError> {
+        Self::read().map_err(WltError::config)
+    }
+
+    /// 读取、解密并检查config.toml，需要加密密码或迁移模板时保存
+    fn read() -> anyhow::Result<Self> {
         let path = std::path::Path::new(CONFIG_PATH);
         if !path.exists() || path.metadata().unwrap().len() == 0 {
             let config = Config::default();
@@ -88,22 +376,206 @@ impl Config {
         } else {
             let content = std::fs::read_to_string(CONFIG_PATH)?;
             let mut config = toml::from_str::<Config>(&content)?;
-            let mut need_save_to_encrypt = false;
-            if let Ok(plain_password) = str_decrypt(&config.网络通密码) {
-                config.网络通密码 = plain_password;
-            } else if !config.网络通密码.is_empty() {
-                need_save_to_encrypt = true;
-            }
-            if let Ok(plain_email_password) = str_decrypt(&config.邮箱密码) {
-                config.邮箱密码 = plain_email_password;
-            } else if !config.邮箱密码.is_empty() {
-                need_save_to_encrypt = true;
-            }
-            if need_save_to_encrypt {
+            let mut need_save_to_encrypt = decrypt_password(&mut config.网络通密码);
+            need_save_to_encrypt |= decrypt_password(&mut config.邮箱密码);
+            for record in config.DDNS.iter_mut() {
+                need_save_to_encrypt |= decrypt_password(&mut record.密钥);
+            }
+            for webhook in config.Webhook.iter_mut() {
+                need_save_to_encrypt |= decrypt_password(&mut webhook.签名密钥);
+            }
+            for channel in config.通知渠道.iter_mut() {
+                need_save_to_encrypt |= decrypt_password(&mut channel.令牌);
+                need_save_to_encrypt |= decrypt_password(&mut channel.签名密钥);
+            }
+            for profile in config.profile.values_mut() {
+                for password in [&mut profile.网络通密码, &mut profile.邮箱密码]
+                    .into_iter()
+                    .flatten()
+                {
+                    need_save_to_encrypt |= decrypt_password(password);
+                }
+            }
+            let mut need_save_to_migrate = migrate_template(&mut config.邮件主题);
+            need_save_to_migrate |= migrate_template(&mut config.邮件内容);
+            for profile in config.profile.values_mut() {
+                for template in [&mut profile.邮件主题, &mut profile.邮件内容]
+                    .into_iter()
+                    .flatten()
+                {
+                    need_save_to_migrate |= migrate_template(template);
+                }
+            }
+            for policy in config.出口策略.iter() {
+                policy.validate()?;
+            }
+            for prefix in config.IPv6前缀.iter() {
+                parse_prefix(prefix)?;
+            }
+            for source in config.IP来源.iter() {
+                source.validate()?;
+            }
+            for record in config.DDNS.iter() {
+                record.validate()?;
+            }
+            for webhook in config.Webhook.iter() {
+                webhook.validate()?;
+            }
+            for channel in config.通知渠道.iter() {
+                channel.validate()?;
+            }
+            template::validate("邮件主题", &config.邮件主题)?;
+            template::validate("邮件内容", &config.邮件内容)?;
+            for profile in config.profile.values() {
+                if let Some(subject) = &profile.邮件主题 {
+                    template::validate("邮件主题", subject)?;
+                }
+                if let Some(content) = &profile.邮件内容 {
+                    template::validate("邮件内容", content)?;
+                }
+            }
+            for template in config.通知模板.iter() {
+                template.validate()?;
+            }
+            for family in [IpFamily::IPv4, IpFamily::IPv6] {
+                let count = config.IP来源.iter().filter(|s| s.协议 == family).count();
+                if count > 0 && !(1..=count).contains(&config.IP一致数量) {
+                    anyhow::bail!("IP一致数量应为1到{}个（{}的来源数量）", count, family);
+                }
+            }
+            if let Some(name) = config
+                .profile
+                .keys()
+                .find(|name| !is_valid_profile_name(name))
+            {
+                anyhow::bail!("profile的名字只能包含英文字母、数字、-和_: {}", name);
+            }
+            // 检查通过后再保存，避免有错误的配置被改写
+            if need_save_to_encrypt || need_save_to_migrate {
                 config.save()?;
             }
-
             Ok(config)
         }
     }
+
+    /// 读取配置，并用选择的profile覆盖，返回覆盖后的配置及选择的profile：
+    /// profile为None时使用默认profile，默认profile也为空时不使用profile
+    pub fn load_profile(profile: Option<&str>) -> Result<(Self, Option<String>), WltError> {
+        let config = Self::load()?;
+        let profile = profile
+            .map(str::to_owned)
+            .or_else(|| Some(config.默认profile.clone()).filter(|name| !name.is_empty()));
+        Ok((config.with_profile(profile.as_deref())?, profile))
+    }
+
+    /// 用名为name的profile中设置的项覆盖外层的同名设置
+    pub fn with_profile(&self, name: Option<&str>) -> Result<Self, WltError> {
+        let mut config = self.clone();
+        let Some(name) = name else {
+            return Ok(config);
+        };
+        let profile = self.profile.get(name).ok_or_else(|| no_profile(name))?;
+        macro_rules! overlay {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(value) = &profile.$field {
+                        config.$field = value.clone();
+                    }
+                )*
+            };
+        }
+        overlay!(
+            网络通用户名,
+            网络通密码,
+            网络通出口,
+            网络通使用时限,
+            邮箱服务器,
+            邮箱端口,
+            邮箱加密,
+            邮箱用户名,
+            邮箱密码,
+            发件人名称,
+            发件人地址,
+            邮件发送列表,
+            邮件主题,
+            邮件内容
+        );
+        Ok(config)
+    }
+
+    /// 把出口和使用时限保存到config.toml，profile不为None时保存到对应的profile中
+    pub fn save_exit(profile: Option<&str>, exit: u8, exp: u32) -> Result<(), WltError> {
+        let mut config = Self::load()?;
+        match profile {
+            Some(name) => {
+                let profile = config
+                    .profile
+                    .get_mut(name)
+                    .ok_or_else(|| no_profile(name))?;
+                profile.网络通出口 = Some(exit);
+                profile.网络通使用时限 = Some(exp);
+            }
+            None => {
+                config.网络通出口 = exit;
+                config.网络通使用时限 = exp;
+            }
+        }
+        config.save().map_err(WltError::config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+"网络通用户名" = "shared"
+"网络通出口" = 8
+"邮件发送列表" = ["shared@example.com"]
+"默认profile" = "alice"
+
+[profile.alice]
+"网络通用户名" = "alice"
+"网络通出口" = 0
+
+[profile.bob]
+"网络通用户名" = "bob"
+"邮件发送列表" = ["bob@example.com"]
+"邮箱加密" = "STARTTLS"
+"#;
+
+    #[test]
+    fn profile_overrides_only_set_fields() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+
+        let alice = config.with_profile(Some("alice")).unwrap();
+        assert_eq!(alice.网络通用户名, "alice");
+        assert_eq!(alice.网络通出口, 0);
+        assert_eq!(alice.邮件发送列表, ["shared@example.com"]);
+
+        let bob = config.with_profile(Some("bob")).unwrap();
+        assert_eq!(bob.网络通用户名, "bob");
+        assert_eq!(bob.网络通出口, 8);
+        assert_eq!(bob.邮件发送列表, ["bob@example.com"]);
+        assert_eq!(bob.邮箱加密, SmtpEncryption::StartTls);
+        assert_eq!(alice.邮箱加密, SmtpEncryption::Tls);
+
+        let shared = config.with_profile(None).unwrap();
+        assert_eq!(shared.网络通用户名, "shared");
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let e = config.with_profile(Some("carol")).err().unwrap();
+        assert_eq!(e.to_string(), "config.toml中没有名为carol的profile");
+    }
+
+    #[test]
+    fn profile_names_are_restricted() {
+        assert!(is_valid_profile_name("alice_2-b"));
+        assert!(!is_valid_profile_name(""));
+        assert!(!is_valid_profile_name("爱丽丝"));
+        assert!(!is_valid_profile_name("a b"));
+    }
 }
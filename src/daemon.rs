@@ -0,0 +1,155 @@
+use std::{sync::mpsc, time::Duration};
+
+use chrono::Local;
+
+use crate::check::{check_wlt, is_timeout, new_wlt_client, notify_error, time_until_renewal};
+use crate::config::Config;
+use crate::data::Data;
+use crate::log::{log, log_append};
+
+enum Wake {
+    Shutdown,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    NetworkChanged,
+}
+
+/// 连续超时时检测间隔翻倍，但不超过最大检测间隔
+fn backoff_interval(interval: u64, max_interval: u64, timeout_count: u32) -> Duration {
+    let factor = 1u64 << timeout_count.min(16);
+    Duration::from_secs(
+        interval
+            .saturating_mul(factor)
+            .min(max_interval.max(interval)),
+    )
+}
+
+/// 网络通快要到期时提前醒来续期，但至少间隔1分钟，避免续期提前时间大于使用时限时频繁开通网络
+fn renewal_interval(interval: Duration, until_renewal: Option<Duration>) -> Duration {
+    match until_renewal {
+        Some(until_renewal) => interval.min(until_renewal.max(Duration::from_secs(60))),
+        None => interval,
+    }
+}
+
+/// 连续超时3、6、12、24……次时才通知，避免断网期间频繁发送邮件
+#[allow(clippy::manual_is_multiple_of)]
+pub fn need_notify_timeout(timeout_count: u32) -> bool {
+    timeout_count % 3 == 0 && (timeout_count / 3).is_power_of_two()
+}
+
+/// 常驻运行，复用同一个WltClient，收到SIGINT/SIGTERM（Windows下为Ctrl+C）后完成当前检测再退出；
+/// Linux下网络变化时会立即检测一次
+pub fn run_daemon(profile: Option<&str>) -> anyhow::Result<()> {
+    run_loop(profile, true)
+}
+
+/// 前台运行，启动时及网络变化时检测，不定时检测，可以和计划任务一起使用（仅Linux可用）
+pub fn run_watch(profile: Option<&str>) -> anyhow::Result<()> {
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("监听网络变化仅Linux可用");
+    }
+    run_loop(profile, false)
+}
+
+/// poll为false时只在网络变化时检测，并且忽略config.toml中的"监听网络变化"
+fn run_loop(profile: Option<&str>, poll: bool) -> anyhow::Result<()> {
+    let (config, profile) = Config::load_profile(profile)?;
+    let mut data = Data::load(profile.as_deref())?;
+    let mut wlt_client = new_wlt_client(&config, &data)?;
+
+    let (tx, rx) = mpsc::channel();
+    #[cfg(target_os = "linux")]
+    if config.监听网络变化 || !poll {
+        let tx = tx.clone();
+        crate::netwatch::spawn_watcher(move || {
+            let _ = tx.send(Wake::NetworkChanged);
+        })?;
+    }
+    ctrlc::set_handler(move || {
+        let _ = tx.send(Wake::Shutdown);
+    })?;
+
+    match poll {
+        true => log(format!("守护进程启动，检测间隔: {}秒", config.检测间隔)),
+        false => log("开始监听网络变化"),
+    }
+    loop {
+        let interval = match check_wlt(&config, &mut data, &mut wlt_client) {
+            Ok(()) => renewal_interval(
+                Duration::from_secs(config.检测间隔),
+                time_until_renewal(&data, config.续期提前时间, Local::now().naive_local()),
+            ),
+            Err(e) if is_timeout(&e) => {
+                data.连续超时次数 += 1;
+                data.save()?;
+                if need_notify_timeout(data.连续超时次数) {
+                    notify_error(&config, &mut data, &e);
+                } else {
+                    log_append("?");
+                }
+                backoff_interval(config.检测间隔, config.最大检测间隔, data.连续超时次数)
+            }
+            Err(e) => {
+                notify_error(&config, &mut data, &e);
+                Duration::from_secs(config.检测间隔)
+            }
+        };
+        let wake = match poll {
+            true => rx.recv_timeout(interval),
+            false => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match wake {
+            Ok(Wake::Shutdown) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(Wake::NetworkChanged) => log("检测到网络变化"),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+        }
+    }
+
+    data.save()?;
+    match poll {
+        true => log("守护进程退出"),
+        false => log("停止监听网络变化"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let secs = |count| backoff_interval(300, 3600, count).as_secs();
+        assert_eq!(secs(0), 300);
+        assert_eq!(secs(1), 600);
+        assert_eq!(secs(3), 2400);
+        assert_eq!(secs(4), 3600);
+        assert_eq!(secs(u32::MAX), 3600);
+        // 最大检测间隔小于检测间隔时以检测间隔为准
+        assert_eq!(backoff_interval(300, 60, 2).as_secs(), 300);
+    }
+
+    #[test]
+    fn renewal_wakes_up_early() {
+        let interval = Duration::from_secs(300);
+        assert_eq!(renewal_interval(interval, None), interval);
+        assert_eq!(
+            renewal_interval(interval, Some(Duration::from_secs(120))),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            renewal_interval(interval, Some(Duration::from_secs(3600))),
+            interval
+        );
+        assert_eq!(
+            renewal_interval(interval, Some(Duration::ZERO)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn timeout_notified_at_powers_of_two() {
+        let notified: Vec<u32> = (0..=50).filter(|&n| need_notify_timeout(n)).collect();
+        assert_eq!(notified, [3, 6, 12, 24, 48]);
+    }
+}
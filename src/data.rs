@@ -1,38 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Duration, NaiveDateTime};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::WltError;
+use crate::probe::ProbeResult;
 
 const DATA_PATH: &str = "data.toml";
+pub const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// IP历史最多记录的次数
+const IP_HISTORY_LEN: usize = 10;
 const DATA_COMMENT: &str = r#"
 # ipv4：用于记录之前的IPv4地址，当IPv4地址变动时，会自动发送邮件通知
 # ipv6：用于记录之前的IPv6地址，当IPv6地址变动时，会自动发送邮件通知
 # rn：Cookie中的一个字段
 # 连续超时次数: 连续超时次数
+# 网关：上次成功访问的网络通网关
+# 出口：上次探测后选择的出口
+# 探测结果：上次探测各个出口的结果，延迟单位为毫秒，超时为是否因为超时而失败
+# 不可用出口、不可用时间：上次探测失败的首选出口及探测的时间，在"首选出口重试间隔"内继续使用备用出口，首选出口探测成功后清空
+# 开通时间：上次开通网络的时间
+# 到期时间：按照使用时限计算的到期时间，使用时限为永久时为空
+# 域名记录：每条DDNS记录上次成功更新的IP地址
+# IP历史：最近10次IP地址变化的时间和新地址，可以在通知模板中使用
+# 上次错误：上次检测出错时的错误信息，检测恢复正常后会发送恢复通知并清空
+# 错误记录：出错以来每种错误（按指纹区分）出现的次数和通知的时间，用于避免重复通知，检测恢复正常后清空
 "#;
 
+/// 一次IP地址变化，记录在data.toml中
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct IpChange {
+    pub 时间: String,
+    pub IPv4: String,
+    pub IPv6: String,
+}
+
+/// 一种错误的出现和通知情况，记录在data.toml中
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ErrorRecord {
+    pub 指纹: String,
+    /// 最近一次的错误信息
+    pub 错误: String,
+    pub 次数: u32,
+    /// 上次通知之后又出现、还没有通知的次数
+    pub 未通知次数: u32,
+    pub 首次时间: String,
+    pub 上次时间: String,
+    pub 上次通知时间: String,
+}
+
+impl ErrorRecord {
+    /// 汇总通知中的一行，说明上次通知以来没有通知的次数
+    fn digest_line(&self) -> String {
+        format!(
+            "{}以来又出现{}次，最后一次在{}: {}",
+            self.上次通知时间, self.未通知次数, self.上次时间, self.错误
+        )
+    }
+}
+
+/// 错误的指纹，把数字都当作0，使只有时间、端口等不同的错误视为同一种
+pub fn error_fingerprint(error: &str) -> String {
+    let normalized: String = error
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '0' } else { c })
+        .collect();
+    hex::encode(&Sha256::digest(normalized.as_bytes())[..8])
+}
+
+#[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct Data {
     pub ipv4: String,
     pub ipv6: String,
     pub rn: String,
     pub 连续超时次数: u32,
+    pub 网关: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 出口: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub 不可用出口: Option<u8>,
+    pub 不可用时间: String,
+    pub 开通时间: String,
+    pub 到期时间: String,
+    pub 上次错误: String,
+    pub 探测结果: Vec<ProbeResult>,
+    pub IP历史: Vec<IpChange>,
+    pub 错误记录: Vec<ErrorRecord>,
+    pub 域名记录: BTreeMap<String, String>,
+    #[serde(skip)]
+    path: PathBuf,
 }
 
 impl Data {
-    pub fn save(&self) -> anyhow::Result<()> {
-        let data_string = toml::to_string_pretty(self)?;
+    pub fn save(&self) -> Result<(), WltError> {
+        let data_string = toml::to_string_pretty(self).map_err(std::io::Error::other)?;
         let content = format!("{}\n{}", data_string, DATA_COMMENT);
-        std::fs::write(DATA_PATH, content)?;
+        std::fs::write(&self.path, content)?;
         Ok(())
     }
 
-    pub fn load() -> anyhow::Result<Self> {
-        let path = std::path::Path::new(DATA_PATH);
+    /// 记录开通网络的时间，exp为使用时限（秒），为0时表示永久，没有到期时间
+    pub fn record_session(&mut self, now: NaiveDateTime, exp: u32) {
+        self.开通时间 = now.format(TIME_FORMAT).to_string();
+        self.到期时间 = match exp {
+            0 => String::new(),
+            exp => (now + Duration::seconds(exp.into()))
+                .format(TIME_FORMAT)
+                .to_string(),
+        };
+    }
+
+    /// 记录一次IP地址变化，只保留最近的IP_HISTORY_LEN次
+    pub fn record_ip_change(&mut self, now: NaiveDateTime) {
+        self.IP历史.push(IpChange {
+            时间: now.format(TIME_FORMAT).to_string(),
+            IPv4: self.ipv4.clone(),
+            IPv6: self.ipv6.clone(),
+        });
+        let excess = self.IP历史.len().saturating_sub(IP_HISTORY_LEN);
+        self.IP历史.drain(..excess);
+    }
+
+    /// 记录一次错误，返回需要通知的内容：新的错误，或上次通知后间隔window没有再出现的错误，单独通知；
+    /// 间隔window内重复出现的错误不通知，超过window后仍在出现的重复错误汇总成一条通知；window为0时每次都通知
+    pub fn record_error(
+        &mut self,
+        error: &str,
+        now: NaiveDateTime,
+        window: Duration,
+    ) -> Vec<String> {
+        let time = now.format(TIME_FORMAT).to_string();
+        let elapsed = |since: &str| {
+            NaiveDateTime::parse_from_str(since, TIME_FORMAT).map_or(true, |t| now - t >= window)
+        };
+        let fingerprint = error_fingerprint(error);
+        let mut notices = Vec::new();
+        match self.错误记录.iter_mut().find(|r| r.指纹 == fingerprint) {
+            Some(record) => {
+                record.错误 = error.to_owned();
+                record.次数 += 1;
+                record.上次时间 = time.clone();
+                if record.未通知次数 == 0 && elapsed(&record.上次通知时间) {
+                    record.上次通知时间 = time.clone();
+                    notices.push(error.to_owned());
+                } else {
+                    record.未通知次数 += 1;
+                }
+            }
+            None => {
+                self.错误记录.push(ErrorRecord {
+                    指纹: fingerprint,
+                    错误: error.to_owned(),
+                    次数: 1,
+                    未通知次数: 0,
+                    首次时间: time.clone(),
+                    上次时间: time.clone(),
+                    上次通知时间: time.clone(),
+                });
+                notices.push(error.to_owned());
+            }
+        }
+
+        let mut digest = Vec::new();
+        for record in self.错误记录.iter_mut() {
+            if record.未通知次数 > 0 && elapsed(&record.上次通知时间) {
+                digest.push(record.digest_line());
+                record.未通知次数 = 0;
+                record.上次通知时间 = time.clone();
+            }
+        }
+        if !digest.is_empty() {
+            notices.push(format!(
+                "以下错误重复出现（同样的错误{}秒内只通知一次）:\n{}",
+                window.num_seconds(),
+                digest.join("\n")
+            ));
+        }
+        notices
+    }
+
+    /// 恢复正常时清空错误记录，返回还没有通知过的重复错误的汇总
+    pub fn clear_errors(&mut self) -> Option<String> {
+        let digest: Vec<String> = self
+            .错误记录
+            .iter()
+            .filter(|record| record.未通知次数 > 0)
+            .map(ErrorRecord::digest_line)
+            .collect();
+        self.错误记录.clear();
+        match digest.is_empty() {
+            true => None,
+            false => Some(format!(
+                "以下错误重复出现，还没有通知:\n{}",
+                digest.join("\n")
+            )),
+        }
+    }
+
+    /// 发件箱和数据保存在同一个目录，data.toml对应outbox.toml，data.<profile>.toml对应outbox.<profile>.toml
+    pub fn outbox_path(&self) -> Option<PathBuf> {
+        let name = self.path.file_name()?.to_str()?;
+        let rest = name.strip_prefix("data")?;
+        Some(self.path.with_file_name(format!("outbox{}", rest)))
+    }
+
+    /// 首选出口exit在retry_interval内探测失败过，并且当前使用备用出口时，返回这个备用出口
+    pub fn failover_exit(
+        &self,
+        exit: u8,
+        now: NaiveDateTime,
+        retry_interval: Duration,
+    ) -> Option<u8> {
+        if self.不可用出口 != Some(exit) {
+            return None;
+        }
+        let failed_at = NaiveDateTime::parse_from_str(&self.不可用时间, TIME_FORMAT).ok()?;
+        match now - failed_at < retry_interval {
+            true => self.出口.filter(|&backup| backup != exit),
+            false => None,
+        }
+    }
+
+    pub fn session_expiry(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(&self.到期时间, TIME_FORMAT).ok()
+    }
+
+    /// profile不为None时，数据保存在data.<profile>.toml中
+    pub fn load(profile: Option<&str>) -> Result<Self, WltError> {
+        match profile {
+            Some(profile) => Self::load_from(format!("data.{}.toml", profile)),
+            None => Self::load_from(DATA_PATH),
+        }
+    }
+
+    pub fn load_from(path: impl AsRef<Path>) -> Result<Self, WltError> {
+        let path = path.as_ref();
         if !path.exists() || path.metadata().unwrap().len() == 0 {
-            let data = Data::default();
+            let data = Data {
+                path: path.to_owned(),
+                ..Default::default()
+            };
             data.save()?;
             Ok(data)
         } else {
-            let data = std::fs::read_to_string(DATA_PATH)?;
-            let data = toml::from_str::<Data>(&data)?;
+            let data = std::fs::read_to_string(path)?;
+            let mut data = toml::from_str::<Data>(&data)
+                .map_err(|e| WltError::ParseError(format!("{}格式有误: {}", path.display(), e)))?;
+            data.path = path.to_owned();
             Ok(data)
         }
     }
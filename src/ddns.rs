@@ -0,0 +1,447 @@
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::data::Data;
+use crate::dns::{self, TsigKey};
+use crate::error::{is_request_timeout, WltError};
+use crate::ip_provider::IpFamily;
+use crate::log::log;
+use crate::utils::split_header;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DdnsType {
+    #[serde(rename = "RFC2136")]
+    Rfc2136,
+    #[serde(rename = "HTTP")]
+    Http,
+}
+
+/// config.toml中"DDNS"的一项，IP地址变化时更新一条域名记录
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DdnsRecord {
+    pub 类型: DdnsType,
+    pub 域名: String,
+    /// IPv4时更新A记录，IPv6时更新AAAA记录
+    pub 协议: IpFamily,
+    /// 类型为RFC2136时是DNS服务器地址，类型为HTTP时是请求地址，可以使用变量
+    pub 地址: String,
+    /// 类型为RFC2136时域名所在的区域，如"example.com"
+    pub 区域: String,
+    pub TTL: u32,
+    /// 类型为RFC2136时是TSIG密钥名，为空时不签名
+    pub 密钥名: String,
+    /// 类型为RFC2136时是Base64编码的TSIG密钥，类型为HTTP时替换变量{密钥}
+    pub 密钥: String,
+    /// 类型为HTTP时使用，不为空时发送Basic认证，密码为密钥
+    pub 用户名: String,
+    pub 方法: String,
+    /// 如"Authorization: Bearer {密钥}"
+    pub 请求头: Vec<String>,
+    pub 请求体: String,
+    /// 不为空时，响应中包含这个字符串才认为更新成功，如DuckDNS的"OK"
+    pub 成功标志: String,
+    /// 单位为毫秒
+    pub 超时: u64,
+}
+
+impl Default for DdnsRecord {
+    fn default() -> Self {
+        Self {
+            类型: DdnsType::Http,
+            域名: String::new(),
+            协议: IpFamily::IPv4,
+            地址: String::new(),
+            区域: String::new(),
+            TTL: 300,
+            密钥名: String::new(),
+            密钥: String::new(),
+            用户名: String::new(),
+            方法: "GET".to_owned(),
+            请求头: Vec::new(),
+            请求体: String::new(),
+            成功标志: String::new(),
+            超时: 5000,
+        }
+    }
+}
+
+impl DdnsRecord {
+    /// data中记录上次更新结果时使用的名字
+    pub fn key(&self) -> String {
+        format!("{} {}", self.域名, self.协议)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.域名.trim().is_empty() || self.地址.trim().is_empty() {
+            anyhow::bail!("DDNS中需要填写域名和地址");
+        }
+        match self.类型 {
+            DdnsType::Rfc2136 => {
+                let name = self.域名.trim_end_matches('.');
+                let zone = self.区域.trim_end_matches('.');
+                if zone.is_empty() || !(name == zone || name.ends_with(&format!(".{}", zone))) {
+                    anyhow::bail!("DDNS中{}不在区域\"{}\"中", self.域名, self.区域);
+                }
+                self.tsig_key()?;
+            }
+            DdnsType::Http => {
+                reqwest::Method::from_bytes(self.方法.as_bytes())
+                    .with_context(|| format!("DDNS中的方法错误: {}", self.方法))?;
+                for header in self.请求头.iter() {
+                    split_header(header)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tsig_key(&self) -> anyhow::Result<Option<TsigKey>> {
+        if self.密钥名.is_empty() {
+            return Ok(None);
+        }
+        let secret = STANDARD
+            .decode(self.密钥.trim())
+            .with_context(|| format!("DDNS中{}的密钥不是Base64编码", self.域名))?;
+        Ok(Some(TsigKey {
+            name: self.密钥名.clone(),
+            secret,
+        }))
+    }
+
+    /// 替换{IP}、{旧IP}、{域名}、{用户名}、{密钥}
+    fn render(&self, template: &str, ip: &str, old_ip: &str) -> String {
+        self.render_with(template, ip, old_ip, str::to_owned)
+    }
+
+    /// 替换地址中的变量，替换的值经过URL编码
+    fn render_url(&self, template: &str, ip: &str, old_ip: &str) -> String {
+        self.render_with(template, ip, old_ip, |value| {
+            urlencoding::encode(value).into_owned()
+        })
+    }
+
+    fn render_with(
+        &self,
+        template: &str,
+        ip: &str,
+        old_ip: &str,
+        escape: impl Fn(&str) -> String,
+    ) -> String {
+        template
+            .replace("{IP}", &escape(ip))
+            .replace("{旧IP}", &escape(old_ip))
+            .replace("{域名}", &escape(&self.域名))
+            .replace("{用户名}", &escape(&self.用户名))
+            .replace("{密钥}", &escape(&self.密钥))
+    }
+
+    fn update_http(&self, ip: &str, old_ip: &str) -> anyhow::Result<()> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(self.超时))
+            .build()?;
+        let method = reqwest::Method::from_bytes(self.方法.as_bytes())?;
+        let mut request = client.request(method, self.render_url(&self.地址, ip, old_ip));
+        for header in self.请求头.iter() {
+            let (name, value) = split_header(header)?;
+            request = request.header(name, self.render(value, ip, old_ip));
+        }
+        if !self.用户名.is_empty() {
+            request = request.basic_auth(&self.用户名, Some(&self.密钥));
+        }
+        if !self.请求体.is_empty() {
+            request = request.body(self.render(&self.请求体, ip, old_ip));
+        }
+        // 地址中可能包含密钥，不写入日志和通知
+        let response = request.send().map_err(|e| e.without_url())?;
+        let status = response.status();
+        let text = response.text().map_err(|e| e.without_url())?;
+        if !status.is_success() {
+            anyhow::bail!("HTTP状态码: {} {}", status, text.trim());
+        }
+        if !self.成功标志.is_empty() && !text.contains(&self.成功标志) {
+            anyhow::bail!("响应中没有\"{}\": {}", self.成功标志, text.trim());
+        }
+        Ok(())
+    }
+
+    /// 把记录更新为ip，old_ip为上次更新的地址
+    pub fn update(&self, ip: &str, old_ip: &str) -> anyhow::Result<()> {
+        match self.类型 {
+            DdnsType::Rfc2136 => {
+                let addr: IpAddr = ip.parse()?;
+                dns::update(
+                    dns::server_addr(&self.地址)?,
+                    &self.区域,
+                    &self.域名,
+                    addr,
+                    self.TTL,
+                    self.tsig_key()?.as_ref(),
+                    Duration::from_millis(self.超时),
+                )
+            }
+            DdnsType::Http => self.update_http(ip, old_ip),
+        }
+    }
+}
+
+/// 把地址与上次更新的不同的记录更新为data中的地址，成功后记录在data中，失败的记录下次检测时重试；
+/// 失败的记录都是超时时返回WltError::AllTimedOut
+pub fn update_ddns(config: &Config, data: &mut Data) -> anyhow::Result<()> {
+    let mut updated = false;
+    let mut errors = Vec::new();
+    let mut all_timed_out = true;
+    for record in config.DDNS.iter() {
+        let ip = match record.协议 {
+            IpFamily::IPv4 => data.ipv4.clone(),
+            IpFamily::IPv6 => data.ipv6.clone(),
+        };
+        let key = record.key();
+        let old_ip = data.域名记录.get(&key).cloned().unwrap_or_default();
+        if ip.is_empty() || ip == old_ip {
+            continue;
+        }
+        match record.update(&ip, &old_ip) {
+            Ok(()) => {
+                log(format!("DDNS: {} 旧IP: {} 新IP: {}", key, old_ip, ip));
+                data.域名记录.insert(key, ip);
+                updated = true;
+            }
+            Err(e) => {
+                all_timed_out &= is_request_timeout(&e);
+                errors.push(format!("{}: {:#}", key, e));
+            }
+        }
+    }
+    if updated {
+        data.save()?;
+    }
+    if !errors.is_empty() {
+        let error = format!("DDNS更新失败\n{}", errors.join("\n"));
+        return match all_timed_out {
+            true => Err(WltError::AllTimedOut(error).into()),
+            false => Err(anyhow::anyhow!(error)),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, UdpSocket},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::mock_gateway::{closed_url, MockServer, Response};
+
+    fn rfc2136_record(server: &str) -> DdnsRecord {
+        DdnsRecord {
+            类型: DdnsType::Rfc2136,
+            域名: "lab.example.com".to_owned(),
+            地址: server.to_owned(),
+            区域: "example.com".to_owned(),
+            密钥名: "wlt-key".to_owned(),
+            密钥: STANDARD.encode("0123456789abcdef"),
+            超时: 500,
+            ..Default::default()
+        }
+    }
+
+    /// UDP只回复截断的响应，要求改用TCP；TCP回复rcode，收到的更新报文记录在返回值中
+    fn start_update_server(rcode: u8) -> (String, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(addr).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok((_, peer)) = udp.recv_from(&mut buf) {
+                let mut response = buf[..12].to_vec();
+                response[2] |= 0x82;
+                let _ = udp.send_to(&response, peer);
+            }
+        });
+        let tcp_received = received.clone();
+        std::thread::spawn(move || {
+            for stream in tcp.incoming() {
+                let mut stream = stream.unwrap();
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut msg).unwrap();
+                let mut response = msg[..12].to_vec();
+                response[2] |= 0x80;
+                response[3] = rcode;
+                response[4..12].fill(0);
+                tcp_received.lock().unwrap().push(msg);
+                let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&response);
+                stream.write_all(&framed).unwrap();
+            }
+        });
+        (addr.to_string(), received)
+    }
+
+    #[test]
+    fn signs_update_with_tsig() {
+        let mut msg = dns::build_update(
+            0x1234,
+            "example.com",
+            "lab.example.com",
+            "114.214.180.23".parse().unwrap(),
+            300,
+        )
+        .unwrap();
+        let key = TsigKey {
+            name: "wlt-key".to_owned(),
+            secret: b"0123456789abcdef".to_vec(),
+        };
+        dns::sign_tsig(&mut msg, &key, 1_700_000_000).unwrap();
+        assert_eq!(&msg[..12], &[0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 1]);
+        // 用独立实现计算的HMAC-SHA256
+        let mac = hex::decode("04e9c3b7621eff1b268416d00f73129674d606745dc8eec96818510bbc7c5032")
+            .unwrap();
+        let tail = &msg[msg.len() - 6 - 32..msg.len() - 6];
+        assert_eq!(tail, &mac[..]);
+    }
+
+    #[test]
+    fn rfc2136_update_falls_back_to_tcp() {
+        let (server, received) = start_update_server(0);
+        let record = rfc2136_record(&server);
+        record.validate().unwrap();
+        record.update("114.214.180.23", "").unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let msg = &received[0];
+        // 附加部分有一条TSIG记录，A记录的数据在TSIG记录之前
+        assert_eq!(&msg[10..12], &[0, 1]);
+        let tsig_at = msg
+            .windows(9)
+            .position(|w| w == b"\x07wlt-key\x00")
+            .unwrap();
+        assert_eq!(&msg[tsig_at - 4..tsig_at], &[114, 214, 180, 23]);
+    }
+
+    #[test]
+    fn rfc2136_reports_server_errors() {
+        let (server, _) = start_update_server(9);
+        let e = rfc2136_record(&server)
+            .update("114.214.180.23", "")
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "DNS服务器返回错误，RCODE: 9 NOTAUTH");
+    }
+
+    #[test]
+    fn http_update_renders_template() {
+        let server = MockServer::start("/", |request| match request.param("myip").as_deref() {
+            Some("2001:da8:d800::23") => Response::ok("good 2001:da8:d800::23"),
+            _ => Response::ok("badauth"),
+        });
+        let record = DdnsRecord {
+            域名: "lab.example.com".to_owned(),
+            协议: IpFamily::IPv6,
+            地址: format!("{}/nic/update?hostname={{域名}}&myip={{IP}}", server.url),
+            用户名: "user".to_owned(),
+            密钥: "secret".to_owned(),
+            方法: "POST".to_owned(),
+            请求头: vec!["X-Old-IP: {旧IP}".to_owned()],
+            请求体: "{\"content\": \"{IP}\"}".to_owned(),
+            成功标志: "good".to_owned(),
+            ..Default::default()
+        };
+        record.validate().unwrap();
+        record
+            .update("2001:da8:d800::23", "2001:da8:d800::1")
+            .unwrap();
+        assert!(record.update("2001:da8:d800::99", "").is_err());
+
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.param("hostname").unwrap(), "lab.example.com");
+        assert_eq!(request.header("x-old-ip"), Some("2001:da8:d800::1"));
+        assert_eq!(
+            request.header("authorization"),
+            Some(format!("Basic {}", STANDARD.encode("user:secret")).as_str())
+        );
+        assert_eq!(request.body, "{\"content\": \"2001:da8:d800::23\"}");
+    }
+
+    #[test]
+    fn http_update_encodes_url_and_hides_it_in_errors() {
+        let server = MockServer::start("/", |_| Response::ok("OK"));
+        let record = DdnsRecord {
+            域名: "lab".to_owned(),
+            地址: format!(
+                "{}/update?domains={{域名}}&token={{密钥}}&ip={{IP}}",
+                server.url
+            ),
+            密钥: "a&b=c/d+e".to_owned(),
+            成功标志: "OK".to_owned(),
+            ..Default::default()
+        };
+        record.update("114.214.180.23", "").unwrap();
+        let request = &server.requests()[0];
+        assert_eq!(request.param("token").unwrap(), "a&b=c/d+e");
+        assert_eq!(request.param("ip").unwrap(), "114.214.180.23");
+        assert!(request.target.contains("token=a%26b%3Dc%2Fd%2Be&"));
+
+        let record = DdnsRecord {
+            地址: format!("{}?token={{密钥}}", closed_url()),
+            密钥: "topsecret".to_owned(),
+            ..record
+        };
+        let e = record.update("114.214.180.23", "").unwrap_err();
+        assert!(!format!("{:?}", e).contains("topsecret"));
+    }
+
+    #[test]
+    fn updates_only_changed_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start("/", |_| Response::ok("OK"));
+        let config = Config {
+            DDNS: vec![DdnsRecord {
+                域名: "lab.example.com".to_owned(),
+                地址: format!("{}/update?ip={{IP}}", server.url),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        data.ipv4 = "114.214.180.23".to_owned();
+
+        update_ddns(&config, &mut data).unwrap();
+        update_ddns(&config, &mut data).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        assert_eq!(data.域名记录["lab.example.com IPv4"], "114.214.180.23");
+    }
+
+    #[test]
+    fn record_validation() {
+        let mut record = rfc2136_record("127.0.0.1");
+        assert!(record.validate().is_ok());
+        record.区域 = "example.org".to_owned();
+        assert!(record.validate().is_err());
+        record = rfc2136_record("127.0.0.1");
+        record.密钥 = "not base64!".to_owned();
+        assert!(record.validate().is_err());
+        let record = DdnsRecord {
+            域名: "lab.example.com".to_owned(),
+            地址: "http://127.0.0.1/".to_owned(),
+            请求头: vec!["no colon".to_owned()],
+            ..Default::default()
+        };
+        assert!(record.validate().is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const TYPE_SOA: u16 = 6;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+const TSIG_FUDGE: u16 = 300;
+
+/// 把域名编码为DNS报文中的格式：每段前加长度，以0结尾
+pub fn encode_name(buf: &mut Vec<u8>, name: &str) -> anyhow::Result<()> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        buf.push(0);
+        return Ok(());
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("域名格式错误: {}", name);
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+/// 跳过报文中offset处的域名（可能包含压缩指针），返回域名之后的位置
+fn skip_name(msg: &[u8], mut offset: usize) -> anyhow::Result<usize> {
+    loop {
+        let len = *msg
+            .get(offset)
+            .ok_or_else(|| anyhow::anyhow!("DNS响应不完整"))?;
+        match len {
+            0 => return Ok(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+fn read_u16(msg: &[u8], offset: usize) -> anyhow::Result<u16> {
+    match msg.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => anyhow::bail!("DNS响应不完整"),
+    }
+}
+
+/// 生成查询报文，请求递归查询
+pub fn build_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&0x0100u16.to_be_bytes());
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut msg, name)?;
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+/// 生成RFC 2136动态更新报文：先删除name上与ip同类型（A或AAAA）的所有记录，再添加一条指向ip的记录
+pub fn build_update(
+    id: u16,
+    zone: &str,
+    name: &str,
+    ip: IpAddr,
+    ttl: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let (rtype, rdata) = match ip {
+        IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+    };
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&OPCODE_UPDATE.to_be_bytes());
+    // 区域1条，前提条件0条，更新2条，附加0条
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 2, 0, 0]);
+    encode_name(&mut msg, zone)?;
+    msg.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    encode_name(&mut msg, name)?;
+    msg.extend_from_slice(&rtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    encode_name(&mut msg, name)?;
+    msg.extend_from_slice(&rtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&rdata);
+    Ok(msg)
+}
+
+/// TSIG密钥，只支持hmac-sha256算法
+pub struct TsigKey {
+    pub name: String,
+    pub secret: Vec<u8>,
+}
+
+/// 按照RFC 8945对报文签名，在附加部分的末尾添加TSIG记录，time_signed为Unix时间（秒）
+pub fn sign_tsig(msg: &mut Vec<u8>, key: &TsigKey, time_signed: u64) -> anyhow::Result<()> {
+    let mut key_name = Vec::new();
+    encode_name(&mut key_name, &key.name.to_ascii_lowercase())?;
+    let mut algorithm = Vec::new();
+    encode_name(&mut algorithm, TSIG_ALGORITHM)?;
+    let time_signed = &time_signed.to_be_bytes()[2..];
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.secret)?;
+    mac.update(msg);
+    mac.update(&key_name);
+    mac.update(&CLASS_ANY.to_be_bytes());
+    mac.update(&[0, 0, 0, 0]);
+    mac.update(&algorithm);
+    mac.update(time_signed);
+    mac.update(&TSIG_FUDGE.to_be_bytes());
+    // 错误为0，没有其他数据
+    mac.update(&[0, 0, 0, 0]);
+    let mac = mac.finalize().into_bytes();
+
+    let mut rdata = algorithm;
+    rdata.extend_from_slice(time_signed);
+    rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    rdata.extend_from_slice(&msg[..2]);
+    rdata.extend_from_slice(&[0, 0, 0, 0]);
+
+    msg.extend_from_slice(&key_name);
+    msg.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+    msg.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&rdata);
+    let arcount = read_u16(msg, 10)? + 1;
+    msg[10..12].copy_from_slice(&arcount.to_be_bytes());
+    Ok(())
+}
+
+fn rcode_name(rcode: u16) -> &'static str {
+    match rcode {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "未知错误",
+    }
+}
+
+/// 检查响应的ID和RCODE，返回各部分的记录数(问题, 回答, 授权, 附加)
+pub fn parse_header(msg: &[u8], id: u16) -> anyhow::Result<[u16; 4]> {
+    if read_u16(msg, 0)? != id {
+        anyhow::bail!("DNS响应的ID不匹配");
+    }
+    let rcode = read_u16(msg, 2)? & 0x000f;
+    if rcode != 0 {
+        anyhow::bail!("DNS服务器返回错误，RCODE: {} {}", rcode, rcode_name(rcode));
+    }
+    Ok([
+        read_u16(msg, 4)?,
+        read_u16(msg, 6)?,
+        read_u16(msg, 8)?,
+        read_u16(msg, 10)?,
+    ])
+}
+
+/// 从响应的回答部分中取出第一个qtype类型的地址
+pub fn parse_address(msg: &[u8], id: u16, qtype: u16) -> anyhow::Result<IpAddr> {
+    let [qdcount, ancount, _, _] = parse_header(msg, id)?;
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = skip_name(msg, offset)? + 4;
+    }
+    for _ in 0..ancount {
+        offset = skip_name(msg, offset)?;
+        let rtype = read_u16(msg, offset)?;
+        let rdlength = read_u16(msg, offset + 8)? as usize;
+        let rdata = msg
+            .get(offset + 10..offset + 10 + rdlength)
+            .ok_or_else(|| anyhow::anyhow!("DNS响应不完整"))?;
+        offset += 10 + rdlength;
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                return Ok(IpAddr::V4(Ipv4Addr::new(
+                    rdata[0], rdata[1], rdata[2], rdata[3],
+                )))
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let bytes: [u8; 16] = rdata.try_into().expect("length is 16");
+                return Ok(IpAddr::V6(Ipv6Addr::from(bytes)));
+            }
+            _ => (),
+        }
+    }
+    anyhow::bail!("DNS响应中没有所需类型的记录")
+}
+
+/// 随机的报文ID
+pub fn new_id() -> u16 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    (nanos ^ (nanos >> 16)) as u16
+}
+
+/// 解析DNS服务器地址，如"208.67.222.222:53"、"[2620:119:35::35]:53"、"ns1.example.com"，没有端口时使用53
+pub fn server_addr(server: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    let addrs = match server.contains(':') {
+        true => server.to_socket_addrs(),
+        false => (server, 53).to_socket_addrs(),
+    };
+    addrs
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .with_context(|| format!("无法解析DNS服务器地址: {}", server))
+}
+
+/// 通过UDP发送报文并等待响应
+fn exchange_udp(server: SocketAddr, msg: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+    socket.send(msg)?;
+    let mut buf = vec![0; 4096];
+    let len = socket.recv(&mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// 通过TCP发送报文并等待响应，报文前有2字节的长度
+fn exchange_tcp(server: SocketAddr, msg: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = (msg.len() as u16).to_be_bytes().to_vec();
+    request.extend_from_slice(msg);
+    stream.write_all(&request)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// 先通过UDP发送报文，响应被截断（TC位为1）时改用TCP
+pub fn exchange(server: SocketAddr, msg: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let response = exchange_udp(server, msg, timeout)?;
+    match response.get(2) {
+        Some(flags) if flags & 0x02 != 0 => exchange_tcp(server, msg, timeout),
+        _ => Ok(response),
+    }
+}
+
+/// 向server查询name的A或AAAA记录
+pub fn resolve(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> anyhow::Result<IpAddr> {
+    let id = new_id();
+    let query = build_query(id, name, qtype)?;
+    let response = exchange(server, &query, timeout)?;
+    parse_address(&response, id, qtype)
+}
+
+/// 通过RFC 2136动态更新把name的A或AAAA记录设置为ip，key不为None时用TSIG签名；不验证响应的签名
+pub fn update(
+    server: SocketAddr,
+    zone: &str,
+    name: &str,
+    ip: IpAddr,
+    ttl: u32,
+    key: Option<&TsigKey>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let id = new_id();
+    let mut msg = build_update(id, zone, name, ip, ttl)?;
+    if let Some(key) = key {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        sign_tsig(&mut msg, key, now.as_secs())?;
+    }
+    let response = exchange(server, &msg, timeout)?;
+    parse_header(&response, id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    /// 在本机随机端口上运行的DNS服务器，对每个查询调用handler生成响应
+    pub fn start_udp_server(
+        handler: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                if let Some(response) = handler(&buf[..len]) {
+                    let _ = socket.send_to(&response, peer);
+                }
+            }
+        });
+        addr
+    }
+
+    /// 按照查询生成包含一条回答的响应，回答中的域名使用压缩指针指向问题中的域名
+    pub fn answer(query: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xc0, 12]);
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&60u32.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(rdata);
+        response
+    }
+
+    #[test]
+    fn builds_query() {
+        let query = build_query(0x1234, "myip.opendns.com", TYPE_A).unwrap();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[12..17], b"\x04myip");
+        assert_eq!(&query[query.len() - 4..], &[0, 1, 0, 1]);
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn resolves_a_and_aaaa_records() {
+        let server = start_udp_server(|query| {
+            let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+            Some(match qtype {
+                TYPE_A => answer(query, TYPE_A, &[114, 214, 180, 23]),
+                _ => answer(
+                    query,
+                    TYPE_AAAA,
+                    &"2001:da8:d800::23".parse::<Ipv6Addr>().unwrap().octets(),
+                ),
+            })
+        });
+        let timeout = Duration::from_millis(500);
+        assert_eq!(
+            resolve(server, "myip.opendns.com", TYPE_A, timeout)
+                .unwrap()
+                .to_string(),
+            "114.214.180.23"
+        );
+        assert_eq!(
+            resolve(server, "myip.opendns.com", TYPE_AAAA, timeout)
+                .unwrap()
+                .to_string(),
+            "2001:da8:d800::23"
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let server = start_udp_server(|query| {
+            let mut response = query.to_vec();
+            response[3] |= 3; // NXDOMAIN
+            Some(response)
+        });
+        let e = resolve(server, "nx.example.com", TYPE_A, Duration::from_millis(500))
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "DNS服务器返回错误，RCODE: 3 NXDOMAIN");
+
+        let silent = start_udp_server(|_| None);
+        assert!(resolve(silent, "example.com", TYPE_A, Duration::from_millis(200)).is_err());
+    }
+}
@@ -1,43 +1,282 @@
 use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, MultiPart};
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{Message, SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
 
-use crate::log::log;
+use crate::config::Config;
+use crate::error::WltError;
+use crate::notify::{self, Event, Notifier};
 
+/// 连接邮箱服务器的方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SmtpEncryption {
+    /// 直接建立TLS连接，默认端口465
+    #[serde(rename = "TLS")]
+    Tls,
+    /// 先建立明文连接再用STARTTLS升级，默认端口587，服务器不支持STARTTLS时不发送
+    #[serde(rename = "STARTTLS")]
+    StartTls,
+    /// 不加密，默认端口25，只适合内网的中继服务器
+    #[serde(rename = "无")]
+    None,
+}
+
+impl SmtpEncryption {
+    fn default_port(&self) -> u16 {
+        match self {
+            SmtpEncryption::Tls => 465,
+            SmtpEncryption::StartTls => 587,
+            SmtpEncryption::None => 25,
+        }
+    }
+}
+
+/// 按照config中的邮箱设置建立SMTP连接，邮箱用户名为空时不认证
+fn mailer(config: &Config) -> anyhow::Result<SmtpTransport> {
+    let server = config.邮箱服务器.as_str();
+    let builder = match config.邮箱加密 {
+        SmtpEncryption::Tls => SmtpTransport::relay(server)?,
+        SmtpEncryption::StartTls => SmtpTransport::starttls_relay(server)?,
+        SmtpEncryption::None => SmtpTransport::builder_dangerous(server),
+    };
+    let port = match config.邮箱端口 {
+        0 => config.邮箱加密.default_port(),
+        port => port,
+    };
+    let mut builder = builder.port(port);
+    if !config.邮箱用户名.is_empty() {
+        builder = builder.credentials(Credentials::new(
+            config.邮箱用户名.clone(),
+            config.邮箱密码.clone(),
+        ));
+    }
+    Ok(builder.build())
+}
+
+/// 发件人，发件人地址为空时使用邮箱用户名
+fn sender(config: &Config) -> anyhow::Result<Mailbox> {
+    let address = match config.发件人地址.is_empty() {
+        true => &config.邮箱用户名,
+        false => &config.发件人地址,
+    };
+    let name = Some(config.发件人名称.clone()).filter(|name| !name.is_empty());
+    Ok(Mailbox::new(name, address.parse()?))
+}
 
+/// html不为None时发送纯文本和HTML两种内容，由邮件客户端选择显示哪一种
 pub fn send_email(
-    server: &str,
-    username: &str,
-    password: &str,
-    email_to_list: &[String],
+    config: &Config,
     subject: &str,
     body: &str,
-) {
-    if email_to_list.is_empty() {
-        log("没有设置\"邮件发送列表\"，不发送邮件");
-        return;
+    html: Option<&str>,
+) -> Result<(), WltError> {
+    send(config, subject, body, html).map_err(WltError::Email)
+}
+
+fn send(config: &Config, subject: &str, body: &str, html: Option<&str>) -> anyhow::Result<()> {
+    if config.邮件发送列表.is_empty() {
+        anyhow::bail!("没有设置\"邮件发送列表\"，不发送邮件");
     }
 
-    let try_send_email = || -> anyhow::Result<()> {
-        let creds = Credentials::new(username.to_owned(), password.to_owned());
+    let mut email = Message::builder().from(sender(config)?);
+    for mailbox_string in config.邮件发送列表.iter() {
+        email = email.to(mailbox_string.parse()?);
+    }
+    let email = email.subject(subject.to_owned());
+    let email = match html {
+        Some(html) => email.multipart(MultiPart::alternative_plain_html(
+            body.to_owned(),
+            html.to_owned(),
+        ))?,
+        None => email
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_owned())?,
+    };
 
-        let mailer = SmtpTransport::relay(server)?.credentials(creds).build();
+    mailer(config)?.send(&email)?;
 
-        let mut email = Message::builder().from(username.parse()?);
-        for mailbox_string in email_to_list.iter() {
-            email = email.to(mailbox_string.parse()?);
-        }
-        let email = email
-            .subject(subject.to_owned())
-            .header(ContentType::TEXT_PLAIN)
-            .body(body.to_owned())?;
+    Ok(())
+}
 
-        mailer.send(&email)?;
+/// 使用config中的邮箱设置发送邮件
+pub struct EmailNotifier<'a> {
+    pub config: &'a Config,
+}
 
+impl Notifier for EmailNotifier<'_> {
+    fn notify(&self, _: &Event, message: &notify::Message) -> anyhow::Result<()> {
+        send_email(
+            self.config,
+            &message.title,
+            &message.text,
+            message.html.as_deref(),
+        )?;
         Ok(())
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// 模拟的SMTP服务器，记录收到的命令和邮件内容
+    struct MockSmtp {
+        port: u16,
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    /// 处理一个SMTP连接，EHLO时回复extensions，不检查命令的顺序
+    fn serve(stream: TcpStream, extensions: &[&str], recorded: &Mutex<Vec<String>>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut reply = |text: &str| {
+            let _ = writer.write_all(format!("{}\r\n", text).as_bytes());
+        };
+        reply("220 localhost ESMTP");
+        let mut in_data = false;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let text = line.trim_end().to_owned();
+            line.clear();
+            recorded.lock().unwrap().push(text.clone());
+            if in_data {
+                if text == "." {
+                    in_data = false;
+                    reply("250 OK");
+                }
+                continue;
+            }
+            let command = text.to_uppercase();
+            if command.starts_with("EHLO") {
+                let mut lines = vec!["localhost"];
+                lines.extend(extensions);
+                let last = lines.len() - 1;
+                for (i, text) in lines.iter().enumerate() {
+                    reply(&format!("250{}{}", if i == last { ' ' } else { '-' }, text));
+                }
+            } else if command.starts_with("AUTH") {
+                reply("235 Authentication succeeded");
+            } else if command == "DATA" {
+                in_data = true;
+                reply("354 Start mail input");
+            } else if command == "STARTTLS" {
+                // 不支持TLS，直接断开
+                break;
+            } else if command == "QUIT" {
+                reply("221 Bye");
+                break;
+            } else {
+                reply("250 OK");
+            }
+        }
+    }
+
+    impl MockSmtp {
+        /// extensions为EHLO回复的扩展，如"AUTH PLAIN LOGIN"、"STARTTLS"
+        fn start(extensions: &'static [&'static str]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let lines = Arc::new(Mutex::new(Vec::new()));
+            let recorded = lines.clone();
+            // lettre的连接池会另外建立一个空闲连接，所以需要接受多个连接
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { continue };
+                    let recorded = recorded.clone();
+                    std::thread::spawn(move || serve(stream, extensions, &recorded));
+                }
+            });
+            Self { port, lines }
+        }
+
+        fn lines(&self) -> Vec<String> {
+            self.lines.lock().unwrap().clone()
+        }
+    }
+
+    fn smtp_config(port: u16) -> Config {
+        Config {
+            邮箱服务器: "127.0.0.1".to_owned(),
+            邮箱端口: port,
+            邮箱加密: SmtpEncryption::None,
+            邮箱用户名: String::new(),
+            邮箱密码: String::new(),
+            发件人地址: "wlt@example.com".to_owned(),
+            邮件发送列表: vec!["alice@example.com".to_owned()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sends_without_auth() {
+        let server = MockSmtp::start(&["8BITMIME"]);
+        send_email(&smtp_config(server.port), "主题", "内容", None).unwrap();
+        let lines = server.lines();
+        assert!(lines.contains(&"MAIL FROM:<wlt@example.com>".to_owned()));
+        assert!(lines.contains(&"RCPT TO:<alice@example.com>".to_owned()));
+        assert!(!lines.iter().any(|line| line.starts_with("AUTH")));
+        assert!(lines.contains(&"From: wlt@example.com".to_owned()));
+    }
+
+    #[test]
+    fn authenticates_with_display_name() {
+        let server = MockSmtp::start(&["AUTH PLAIN LOGIN"]);
+        let config = Config {
+            邮箱用户名: "user@example.com".to_owned(),
+            邮箱密码: "secret".to_owned(),
+            发件人名称: "网络通".to_owned(),
+            ..smtp_config(server.port)
+        };
+        send_email(&config, "主题", "内容", None).unwrap();
+        let lines = server.lines();
+        // AUTH PLAIN的参数为Base64编码的"\0user@example.com\0secret"
+        assert!(lines.contains(&"AUTH PLAIN AHVzZXJAZXhhbXBsZS5jb20Ac2VjcmV0".to_owned()));
+        assert!(lines.contains(&"MAIL FROM:<wlt@example.com>".to_owned()));
+        assert!(lines.contains(&"From: =?utf-8?b?572R57uc6YCa?= <wlt@example.com>".to_owned()));
+    }
+
+    #[test]
+    fn sends_multipart_html() {
+        let server = MockSmtp::start(&["8BITMIME"]);
+        let html = Some("<p>内容</p>");
+        send_email(&smtp_config(server.port), "主题", "内容", html).unwrap();
+        let lines = server.lines();
+        let content_types: Vec<&String> = lines
+            .iter()
+            .filter(|line| line.starts_with("Content-Type:"))
+            .collect();
+        assert!(content_types[0].starts_with("Content-Type: multipart/alternative;"));
+        assert_eq!(content_types[1], "Content-Type: text/plain; charset=utf-8");
+        assert_eq!(content_types[2], "Content-Type: text/html; charset=utf-8");
+    }
+
+    #[test]
+    fn starttls_refuses_to_send_in_plain_text() {
+        let server = MockSmtp::start(&["AUTH PLAIN LOGIN", "STARTTLS"]);
+        let config = Config {
+            邮箱加密: SmtpEncryption::StartTls,
+            邮箱用户名: "user@example.com".to_owned(),
+            ..smtp_config(server.port)
+        };
+        let e = send_email(&config, "主题", "内容", None).err().unwrap();
+        assert!(matches!(e, WltError::Email(_)));
+        let lines = server.lines();
+        assert!(lines.contains(&"STARTTLS".to_owned()));
+        assert!(!lines.iter().any(|line| line.starts_with("AUTH")));
+    }
 
-    if let Err(e) = try_send_email() {
-        log(format!("发送邮件失败: {}", e));
+    #[test]
+    fn uses_username_as_default_sender() {
+        let config = Config {
+            邮箱用户名: "10000@qq.com".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(sender(&config).unwrap().to_string(), "10000@qq.com");
+        assert_eq!(SmtpEncryption::Tls.default_port(), 465);
     }
 }
@@ -0,0 +1,148 @@
+use thiserror::Error;
+
+/// 网络通任务的错误，按照种类决定是否重试、是否通知以及退出码
+#[derive(Error, Debug)]
+pub enum WltError {
+    /// 访问网关超时，下次检测时可能恢复
+    #[error(transparent)]
+    Timeout(reqwest::Error),
+    /// 探测出口或更新DDNS时每个请求都超时，内容为汇总的错误
+    #[error("{0}")]
+    AllTimedOut(String),
+    /// 连接网关失败或发送请求失败
+    #[error(transparent)]
+    Connect(reqwest::Error),
+    /// 读取或解码响应失败等其他HTTP错误，网关可以连接，不尝试下一个网关
+    #[error(transparent)]
+    Response(reqwest::Error),
+    /// 用户名为空、用户名不存在、密码错误等，reason为网关返回的原因
+    #[error("{reason}")]
+    LoginFailed { reason: String },
+    /// 网关返回了未知的页面或预期之外的状态码
+    #[error("{0}")]
+    UnknownPage(String),
+    /// 页面中没有找到需要的内容，或data.toml等文件的格式有误
+    #[error("{0}")]
+    ParseError(String),
+    /// config.toml有误
+    #[error(transparent)]
+    Config(anyhow::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// 发送邮件失败
+    #[error(transparent)]
+    Email(anyhow::Error),
+}
+
+impl From<reqwest::Error> for WltError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            WltError::Timeout(e)
+        } else if e.is_connect() || e.is_request() {
+            WltError::Connect(e)
+        } else {
+            WltError::Response(e)
+        }
+    }
+}
+
+impl WltError {
+    /// 读写config.toml时的错误，文件读写失败时为Io，其余为Config
+    pub fn config(e: anyhow::Error) -> Self {
+        match e.downcast::<std::io::Error>() {
+            Ok(e) => WltError::Io(e),
+            Err(e) => WltError::Config(e),
+        }
+    }
+
+    /// 连接失败或超时说明网关不可用，可以尝试下一个网关
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, WltError::Timeout(_) | WltError::Connect(_))
+    }
+
+    /// 进程的退出码，参考sysexits.h
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            WltError::Timeout(_) | WltError::AllTimedOut(_) => 75,
+            WltError::Connect(_) | WltError::Email(_) => 69,
+            WltError::LoginFailed { .. } => 77,
+            WltError::UnknownPage(_) | WltError::ParseError(_) | WltError::Response(_) => 76,
+            WltError::Config(_) => 78,
+            WltError::Io(_) => 74,
+        }
+    }
+}
+
+/// 单个HTTP请求、TCP连接或DNS查询超时，用于判断汇总的错误是否都是超时
+pub fn is_request_timeout(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout)
+            || cause.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                // 设置了读取超时的socket超时时，Unix下为WouldBlock
+                matches!(
+                    e.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                )
+            })
+    })
+}
+
+/// 错误是WltError时使用它的退出码，否则为1
+pub fn exit_code(e: &anyhow::Error) -> u8 {
+    e.downcast_ref::<WltError>().map_or(1, WltError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::mock_gateway::{closed_url, MockServer, Response};
+
+    #[test]
+    fn config_errors_are_classified() {
+        let io = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "拒绝访问");
+        assert!(matches!(WltError::config(io.into()), WltError::Io(_)));
+        let e = WltError::config(anyhow::anyhow!("IP一致数量应为1到2个"));
+        assert!(matches!(e, WltError::Config(_)));
+        assert_eq!(e.to_string(), "IP一致数量应为1到2个");
+        assert_eq!(exit_code(&e.into()), 78);
+        assert_eq!(exit_code(&anyhow::anyhow!("其他错误")), 1);
+    }
+
+    #[test]
+    fn reqwest_errors_are_classified() {
+        let client = reqwest::blocking::Client::new();
+        let e: WltError = client.get(closed_url()).send().unwrap_err().into();
+        assert!(matches!(e, WltError::Connect(_)));
+        assert!(e.is_connection_error());
+
+        let server = MockServer::start("/", |_| Response::ok("").status(500));
+        let response = client.get(&server.url).send().unwrap();
+        let e: WltError = response.error_for_status().unwrap_err().into();
+        assert!(matches!(e, WltError::Response(_)));
+        assert!(!e.is_connection_error());
+        assert_eq!(e.exit_code(), 76);
+
+        let server = MockServer::start("/", |_| Response::ok("").delay(Duration::from_secs(2)));
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let e: WltError = client.get(&server.url).send().unwrap_err().into();
+        assert!(matches!(e, WltError::Timeout(_)));
+        assert!(e.is_connection_error());
+    }
+
+    #[test]
+    fn login_failure_displays_reason() {
+        let e = WltError::LoginFailed {
+            reason: "用户名或密码错误".to_owned(),
+        };
+        assert_eq!(e.to_string(), "用户名或密码错误");
+        assert_eq!(e.exit_code(), 77);
+        assert!(!e.is_connection_error());
+    }
+}
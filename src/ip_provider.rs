@@ -0,0 +1,433 @@
+use std::{fmt::Display, net::IpAddr, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::dns::{self, TYPE_A, TYPE_AAAA};
+use crate::ipv6::local_ipv6;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum IpFamily {
+    IPv4,
+    IPv6,
+}
+
+impl Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::IPv4 => write!(f, "IPv4"),
+            IpFamily::IPv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+impl IpFamily {
+    fn matches(&self, ip: &IpAddr) -> bool {
+        matches!(
+            (self, ip),
+            (IpFamily::IPv4, IpAddr::V4(_)) | (IpFamily::IPv6, IpAddr::V6(_))
+        )
+    }
+}
+
+/// IP地址的一个来源
+pub trait IpProvider {
+    /// 用于日志中区分不同的来源
+    fn name(&self) -> String;
+    fn get_ip(&self, family: IpFamily) -> anyhow::Result<IpAddr>;
+}
+
+/// 网络通页面上显示的当前IP地址，只有IPv4
+pub struct WltPage<'a> {
+    pub ip: &'a str,
+}
+
+impl IpProvider for WltPage<'_> {
+    fn name(&self) -> String {
+        "网络通页面".to_owned()
+    }
+
+    fn get_ip(&self, family: IpFamily) -> anyhow::Result<IpAddr> {
+        if family != IpFamily::IPv4 {
+            anyhow::bail!("网络通页面上只有IPv4地址");
+        }
+        self.ip
+            .parse()
+            .with_context(|| format!("网络通页面上的IP地址格式错误: {}", self.ip))
+    }
+}
+
+/// 公网IPv4地址，不包括私有、环回、链路本地、运营商级NAT（100.64.0.0/10）等地址
+fn is_public_ipv4(addr: &std::net::Ipv4Addr) -> bool {
+    let [a, b, ..] = addr.octets();
+    !(addr.is_private()
+        || addr.is_loopback()
+        || addr.is_link_local()
+        || addr.is_unspecified()
+        || addr.is_broadcast()
+        || addr.is_documentation()
+        || addr.is_multicast()
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+/// 本机网卡上的地址，IPv6按照"IPv6网卡"、"IPv6前缀"等设置选择，IPv4选择第一个公网地址
+pub struct LocalInterface<'a> {
+    pub config: &'a Config,
+}
+
+impl IpProvider for LocalInterface<'_> {
+    fn name(&self) -> String {
+        "本机网卡".to_owned()
+    }
+
+    fn get_ip(&self, family: IpFamily) -> anyhow::Result<IpAddr> {
+        let ip =
+            match family {
+                IpFamily::IPv4 => if_addrs::get_if_addrs()?.into_iter().find_map(|interface| {
+                    match interface.addr {
+                        if_addrs::IfAddr::V4(addr) if is_public_ipv4(&addr.ip) => {
+                            Some(IpAddr::V4(addr.ip))
+                        }
+                        _ => None,
+                    }
+                }),
+                IpFamily::IPv6 => local_ipv6(self.config)?.map(IpAddr::V6),
+            };
+        ip.with_context(|| format!("本机网卡上没有{}地址", family))
+    }
+}
+
+/// 返回纯文本IP地址的网页，如"http://api.ipify.org/"
+pub struct HttpEcho<'a> {
+    pub url: &'a str,
+    pub timeout: Duration,
+}
+
+impl IpProvider for HttpEcho<'_> {
+    fn name(&self) -> String {
+        self.url.to_owned()
+    }
+
+    fn get_ip(&self, family: IpFamily) -> anyhow::Result<IpAddr> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .no_proxy()
+            .build()?;
+        let text = client.get(self.url).send()?.error_for_status()?.text()?;
+        match text.trim().parse() {
+            Ok(ip) if family.matches(&ip) => Ok(ip),
+            _ => anyhow::bail!("{}返回的不是{}地址: {}", self.url, family, text),
+        }
+    }
+}
+
+/// 向DNS服务器查询特殊域名得到本机的地址，如OpenDNS的myip.opendns.com，
+/// 查询IPv6地址时需要通过IPv6连接DNS服务器
+pub struct DnsLookup<'a> {
+    /// 如"208.67.222.222:53"，没有端口时使用53
+    pub server: &'a str,
+    pub name: &'a str,
+    pub timeout: Duration,
+}
+
+impl IpProvider for DnsLookup<'_> {
+    fn name(&self) -> String {
+        format!("DNS {} {}", self.server, self.name)
+    }
+
+    fn get_ip(&self, family: IpFamily) -> anyhow::Result<IpAddr> {
+        let qtype = match family {
+            IpFamily::IPv4 => TYPE_A,
+            IpFamily::IPv6 => TYPE_AAAA,
+        };
+        dns::resolve(
+            dns::server_addr(self.server)?,
+            self.name,
+            qtype,
+            self.timeout,
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum IpSourceType {
+    #[serde(rename = "网络通")]
+    Wlt,
+    #[serde(rename = "网卡")]
+    Interface,
+    #[serde(rename = "网页")]
+    Http,
+    #[serde(rename = "DNS")]
+    Dns,
+}
+
+/// config.toml中"IP来源"的一项
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct IpSource {
+    pub 类型: IpSourceType,
+    pub 协议: IpFamily,
+    /// 类型为网页时是网页地址，类型为DNS时是DNS服务器地址
+    pub 地址: String,
+    /// 类型为DNS时查询的域名
+    pub 域名: String,
+    /// 单位为毫秒
+    pub 超时: u64,
+}
+
+impl Default for IpSource {
+    fn default() -> Self {
+        Self {
+            类型: IpSourceType::Wlt,
+            协议: IpFamily::IPv4,
+            地址: String::new(),
+            域名: "myip.opendns.com".to_owned(),
+            超时: 5000,
+        }
+    }
+}
+
+impl IpSource {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.类型 {
+            IpSourceType::Wlt if self.协议 == IpFamily::IPv6 => {
+                anyhow::bail!("IP来源中类型为网络通时协议只能为IPv4")
+            }
+            IpSourceType::Http | IpSourceType::Dns if self.地址.trim().is_empty() => {
+                anyhow::bail!("IP来源中类型为网页或DNS时需要填写地址")
+            }
+            IpSourceType::Dns if self.域名.trim().is_empty() => {
+                anyhow::bail!("IP来源中类型为DNS时需要填写域名")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// wlt_ip为网络通页面上的IP地址
+    pub fn provider<'a>(&'a self, config: &'a Config, wlt_ip: &'a str) -> Box<dyn IpProvider + 'a> {
+        let timeout = Duration::from_millis(self.超时);
+        match self.类型 {
+            IpSourceType::Wlt => Box::new(WltPage { ip: wlt_ip }),
+            IpSourceType::Interface => Box::new(LocalInterface { config }),
+            IpSourceType::Http => Box::new(HttpEcho {
+                url: &self.地址,
+                timeout,
+            }),
+            IpSourceType::Dns => Box::new(DnsLookup {
+                server: &self.地址,
+                name: &self.域名,
+                timeout,
+            }),
+        }
+    }
+}
+
+/// 依次询问所有来源，返回得到的来源最多的地址（相同时取先得到的），
+/// 这个地址的来源少于required个时认为检测失败，避免某个来源出错时误报IP变化
+pub fn consensus(
+    providers: &[Box<dyn IpProvider + '_>],
+    family: IpFamily,
+    required: usize,
+) -> anyhow::Result<IpAddr> {
+    let mut votes: Vec<(IpAddr, usize)> = Vec::new();
+    let mut details = Vec::new();
+    for provider in providers {
+        match provider.get_ip(family) {
+            Ok(ip) => {
+                details.push(format!("{}: {}", provider.name(), ip));
+                match votes.iter_mut().find(|(voted, _)| *voted == ip) {
+                    Some((_, count)) => *count += 1,
+                    None => votes.push((ip, 1)),
+                }
+            }
+            Err(e) => details.push(format!("{}: {:#}", provider.name(), e)),
+        }
+    }
+    let mut best: Option<(IpAddr, usize)> = None;
+    for (ip, count) in votes {
+        match best {
+            Some((_, best_count)) if count <= best_count => {}
+            _ => best = Some((ip, count)),
+        }
+    }
+    match best {
+        Some((ip, count)) if count >= required => Ok(ip),
+        _ => anyhow::bail!(
+            "没有{}个来源得到相同的{}地址\n{}",
+            required,
+            family,
+            details.join("\n")
+        ),
+    }
+}
+
+/// 按照"IP来源"中协议为family的来源检测IP地址，没有这样的来源时返回None
+pub fn discover_ip(
+    config: &Config,
+    family: IpFamily,
+    wlt_ip: &str,
+) -> Option<anyhow::Result<String>> {
+    let providers: Vec<Box<dyn IpProvider + '_>> = config
+        .IP来源
+        .iter()
+        .filter(|source| source.协议 == family)
+        .map(|source| source.provider(config, wlt_ip))
+        .collect();
+    if providers.is_empty() {
+        return None;
+    }
+    Some(consensus(&providers, family, config.IP一致数量).map(|ip| ip.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::tests::{answer, start_udp_server};
+    use crate::mock_gateway::{MockServer, Response};
+
+    struct Fixed(&'static str);
+
+    impl IpProvider for Fixed {
+        fn name(&self) -> String {
+            "固定".to_owned()
+        }
+
+        fn get_ip(&self, _: IpFamily) -> anyhow::Result<IpAddr> {
+            match self.0 {
+                "" => anyhow::bail!("出错"),
+                ip => Ok(ip.parse()?),
+            }
+        }
+    }
+
+    fn fixed(ips: &[&'static str]) -> Vec<Box<dyn IpProvider>> {
+        ips.iter()
+            .map(|&ip| Box::new(Fixed(ip)) as Box<dyn IpProvider>)
+            .collect()
+    }
+
+    #[test]
+    fn consensus_requires_agreeing_sources() {
+        let providers = fixed(&["114.214.180.23", "10.0.0.1", "114.214.180.23", ""]);
+        assert_eq!(
+            consensus(&providers, IpFamily::IPv4, 2)
+                .unwrap()
+                .to_string(),
+            "114.214.180.23"
+        );
+        assert!(consensus(&providers, IpFamily::IPv4, 3).is_err());
+
+        let providers = fixed(&["10.0.0.1", "114.214.180.23"]);
+        assert_eq!(
+            consensus(&providers, IpFamily::IPv4, 1)
+                .unwrap()
+                .to_string(),
+            "10.0.0.1"
+        );
+        assert!(consensus(&providers, IpFamily::IPv4, 2).is_err());
+        assert!(consensus(&fixed(&["", ""]), IpFamily::IPv4, 1).is_err());
+    }
+
+    #[test]
+    fn http_echo_checks_family() {
+        let v6 = MockServer::start("/", |_| Response::ok("2001:da8:d800::abcd\n"));
+        let v4 = MockServer::start("/", |_| Response::ok("114.214.180.23"));
+        let timeout = Duration::from_millis(500);
+        let provider = HttpEcho {
+            url: &v6.url,
+            timeout,
+        };
+        assert_eq!(
+            provider.get_ip(IpFamily::IPv6).unwrap().to_string(),
+            "2001:da8:d800::abcd"
+        );
+        assert!(provider.get_ip(IpFamily::IPv4).is_err());
+        let provider = HttpEcho {
+            url: &v4.url,
+            timeout,
+        };
+        assert_eq!(
+            provider.get_ip(IpFamily::IPv4).unwrap().to_string(),
+            "114.214.180.23"
+        );
+    }
+
+    #[test]
+    fn discovers_ip_from_configured_sources() {
+        let echo = MockServer::start("/", |_| Response::ok("114.214.180.23"));
+        let dns_server =
+            start_udp_server(|query| Some(answer(query, TYPE_A, &[114, 214, 180, 24])));
+        let mut config = Config {
+            IP来源: vec![
+                IpSource::default(),
+                IpSource {
+                    类型: IpSourceType::Http,
+                    地址: echo.url.clone(),
+                    ..Default::default()
+                },
+                IpSource {
+                    类型: IpSourceType::Dns,
+                    地址: dns_server.to_string(),
+                    ..Default::default()
+                },
+            ],
+            IP一致数量: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            discover_ip(&config, IpFamily::IPv4, "114.214.180.23")
+                .unwrap()
+                .unwrap(),
+            "114.214.180.23"
+        );
+        // 网络通页面出错时，网页和DNS的结果不一致
+        assert!(discover_ip(&config, IpFamily::IPv4, "114.214.180.99")
+            .unwrap()
+            .is_err());
+        assert!(discover_ip(&config, IpFamily::IPv6, "114.214.180.23").is_none());
+
+        config.IP一致数量 = 1;
+        assert_eq!(
+            discover_ip(&config, IpFamily::IPv4, "").unwrap().unwrap(),
+            "114.214.180.23"
+        );
+    }
+
+    #[test]
+    fn source_validation() {
+        assert!(IpSource::default().validate().is_ok());
+        let invalid = [
+            IpSource {
+                协议: IpFamily::IPv6,
+                ..Default::default()
+            },
+            IpSource {
+                类型: IpSourceType::Http,
+                ..Default::default()
+            },
+            IpSource {
+                类型: IpSourceType::Dns,
+                地址: "208.67.222.222".to_owned(),
+                域名: String::new(),
+                ..Default::default()
+            },
+        ];
+        for source in invalid {
+            assert!(source.validate().is_err());
+        }
+        let source: IpSource = toml::from_str(
+            r#"
+"类型" = "DNS"
+"协议" = "IPv6"
+"地址" = "[2620:119:35::35]:53"
+"#,
+        )
+        .unwrap();
+        assert_eq!(source.类型, IpSourceType::Dns);
+        assert_eq!(source.域名, "myip.opendns.com");
+        assert!(is_public_ipv4(&"114.214.180.23".parse().unwrap()));
+        assert!(!is_public_ipv4(&"100.64.1.1".parse().unwrap()));
+        assert!(!is_public_ipv4(&"192.168.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{net::Ipv6Addr, time::Duration};
+
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::ip_provider::{HttpEcho, IpFamily, IpProvider};
+
+/// 本机网卡上的一个IPv6地址
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ipv6Candidate {
+    pub addr: Ipv6Addr,
+    pub interface: String,
+    /// 隐私扩展生成的临时地址
+    pub temporary: bool,
+    /// 已过期但仍可使用的地址
+    pub deprecated: bool,
+    /// 重复地址检测未完成或失败，不能使用
+    pub tentative: bool,
+}
+
+/// 解析"2001:da8::/32"形式的前缀
+pub fn parse_prefix(prefix: &str) -> anyhow::Result<(Ipv6Addr, u32)> {
+    let error = || format!("IPv6前缀格式错误，应为\"2001:da8::/32\"的形式: {}", prefix);
+    let (addr, len) = prefix.trim().split_once('/').with_context(error)?;
+    let addr: Ipv6Addr = addr.parse().with_context(error)?;
+    let len: u32 = len.parse().with_context(error)?;
+    if len > 128 {
+        anyhow::bail!(error());
+    }
+    Ok((addr, len))
+}
+
+fn in_prefix(addr: &Ipv6Addr, (prefix, len): (Ipv6Addr, u32)) -> bool {
+    let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+    u128::from(*addr) & mask == u128::from(prefix) & mask
+}
+
+/// 全球单播地址，不包括环回、链路本地、唯一本地（fc00::/7）、组播等地址
+fn is_global_unicast(addr: &Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+    (first & 0xe000) == 0x2000 && !in_prefix(addr, ("2001:db8::".parse().unwrap(), 32))
+}
+
+/// 按照配置选择地址：
+/// 只考虑"IPv6网卡"中的网卡（为空时考虑所有网卡）；
+/// 设置了"IPv6前缀"时只考虑其中的地址，否则只考虑全球单播地址；
+/// 优先选择未过期的地址，"IPv6优先稳定地址"为true时优先选择非临时地址
+pub fn select_ipv6(
+    candidates: &[Ipv6Candidate],
+    config: &Config,
+) -> anyhow::Result<Option<Ipv6Addr>> {
+    let prefixes = config
+        .IPv6前缀
+        .iter()
+        .map(|prefix| parse_prefix(prefix))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut candidates: Vec<&Ipv6Candidate> = candidates
+        .iter()
+        .filter(|candidate| !candidate.tentative)
+        .filter(|candidate| {
+            config.IPv6网卡.is_empty() || config.IPv6网卡.contains(&candidate.interface)
+        })
+        .filter(|candidate| {
+            if prefixes.is_empty() {
+                is_global_unicast(&candidate.addr)
+            } else {
+                prefixes
+                    .iter()
+                    .any(|prefix| in_prefix(&candidate.addr, *prefix))
+            }
+        })
+        .collect();
+    candidates.sort_by_key(|candidate| {
+        (
+            candidate.deprecated,
+            config.IPv6优先稳定地址 && candidate.temporary,
+        )
+    });
+    Ok(candidates.first().map(|candidate| candidate.addr))
+}
+
+/// 解析/proc/net/if_inet6，每行为：地址 网卡序号 前缀长度 范围 标志 网卡名
+#[cfg(target_os = "linux")]
+fn parse_if_inet6(text: &str) -> Vec<Ipv6Candidate> {
+    const IFA_F_TEMPORARY: u32 = 0x01;
+    const IFA_F_DADFAILED: u32 = 0x08;
+    const IFA_F_DEPRECATED: u32 = 0x20;
+    const IFA_F_TENTATIVE: u32 = 0x40;
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [addr, _, _, _, flags, interface] = fields[..] else {
+                return None;
+            };
+            let addr = Ipv6Addr::from(u128::from_str_radix(addr, 16).ok()?);
+            let flags = u32::from_str_radix(flags, 16).ok()?;
+            Some(Ipv6Candidate {
+                addr,
+                interface: interface.to_owned(),
+                temporary: flags & IFA_F_TEMPORARY != 0,
+                deprecated: flags & IFA_F_DEPRECATED != 0,
+                tentative: flags & (IFA_F_TENTATIVE | IFA_F_DADFAILED) != 0,
+            })
+        })
+        .collect()
+}
+
+/// 列出本机网卡上的IPv6地址，Linux下可以区分临时地址、过期地址
+#[cfg(target_os = "linux")]
+fn local_ipv6_candidates() -> anyhow::Result<Vec<Ipv6Candidate>> {
+    let text = std::fs::read_to_string("/proc/net/if_inet6")?;
+    Ok(parse_if_inet6(&text))
+}
+
+/// 列出本机网卡上的IPv6地址，其他系统下无法区分临时地址、过期地址
+#[cfg(not(target_os = "linux"))]
+fn local_ipv6_candidates() -> anyhow::Result<Vec<Ipv6Candidate>> {
+    Ok(if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter_map(|interface| match interface.addr {
+            if_addrs::IfAddr::V6(addr) => Some(Ipv6Candidate {
+                addr: addr.ip,
+                interface: interface.name,
+                temporary: false,
+                deprecated: false,
+                tentative: false,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+/// 按照配置从本机网卡选择IPv6地址
+pub fn local_ipv6(config: &Config) -> anyhow::Result<Option<Ipv6Addr>> {
+    select_ipv6(&local_ipv6_candidates()?, config)
+}
+
+/// 先从本机网卡选择IPv6地址，找不到时依次通过"IPv6查询地址"中的网页查询
+pub fn get_ipv6(config: &Config) -> anyhow::Result<String> {
+    if let Some(addr) = local_ipv6(config)? {
+        return Ok(addr.to_string());
+    }
+    let timeout = Duration::from_millis(config.IPv6查询超时);
+    let mut errors = Vec::new();
+    for url in config.IPv6查询地址.iter() {
+        match (HttpEcho { url, timeout }).get_ip(IpFamily::IPv6) {
+            Ok(addr) => return Ok(addr.to_string()),
+            Err(e) => errors.push(format!("{}: {:#}", url, e)),
+        }
+    }
+    anyhow::bail!("没有找到IPv6地址\n{}", errors.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(addr: &str, interface: &str, temporary: bool) -> Ipv6Candidate {
+        Ipv6Candidate {
+            addr: addr.parse().unwrap(),
+            interface: interface.to_owned(),
+            temporary,
+            deprecated: false,
+            tentative: false,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_proc_if_inet6() {
+        let text = "\
+00000000000000000000000000000001 01 80 10 80       lo
+20010da8d800112300000000000000aa 02 40 00 01     eth0
+fe800000000000000211223344556677 02 40 20 80     eth0
+20010da8d80011230211223344556677 02 40 00 100    eth0
+20010da8d8001123000000000000bbbb 02 40 00 60     eth0
+";
+        let candidates = parse_if_inet6(text);
+        assert_eq!(candidates.len(), 5);
+        assert_eq!(candidates[0].addr, Ipv6Addr::LOCALHOST);
+        assert_eq!(candidates[0].interface, "lo");
+        assert_eq!(
+            candidates[1].addr,
+            "2001:da8:d800:1123::aa".parse::<Ipv6Addr>().unwrap()
+        );
+        assert!(candidates[1].temporary);
+        assert!(!candidates[3].temporary);
+        assert!(candidates[4].deprecated && candidates[4].tentative);
+
+        let config = Config::default();
+        assert_eq!(
+            select_ipv6(&candidates, &config)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "2001:da8:d800:1123:211:2233:4455:6677"
+        );
+    }
+
+    #[test]
+    fn prefers_stable_global_address() {
+        let candidates = [
+            candidate("fe80::1", "eth0", false),
+            candidate("fd00::1", "eth0", false),
+            candidate("2001:da8:d800::1234", "eth0", true),
+            candidate("2001:da8:d800::1", "eth0", false),
+        ];
+        let config = Config::default();
+        assert_eq!(
+            select_ipv6(&candidates, &config)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "2001:da8:d800::1"
+        );
+        let config = Config {
+            IPv6优先稳定地址: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            select_ipv6(&candidates, &config)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "2001:da8:d800::1234"
+        );
+    }
+
+    #[test]
+    fn filters_by_interface_and_prefix() {
+        let candidates = [
+            candidate("2409:8a00::1", "wlan0", false),
+            candidate("2001:da8:d800::1", "eth0", false),
+            candidate("fd12:3456::1", "eth1", false),
+        ];
+        let config = Config {
+            IPv6网卡: vec!["eth0".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            select_ipv6(&candidates, &config)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "2001:da8:d800::1"
+        );
+        let config = Config {
+            IPv6前缀: vec!["fd12:3456::/32".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            select_ipv6(&candidates, &config)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "fd12:3456::1"
+        );
+        let config = Config {
+            IPv6网卡: vec!["eth2".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(select_ipv6(&candidates, &config).unwrap(), None);
+    }
+
+    #[test]
+    fn prefix_parsing() {
+        assert!(parse_prefix("2001:da8::/32").is_ok());
+        assert!(parse_prefix("2001:da8::/129").is_err());
+        assert!(parse_prefix("2001:da8::").is_err());
+        assert!(in_prefix(
+            &"2001:da8:1::1".parse().unwrap(),
+            parse_prefix("2001:da8::/32").unwrap()
+        ));
+        assert!(in_prefix(
+            &"::1".parse().unwrap(),
+            parse_prefix("::/0").unwrap()
+        ));
+    }
+}
@@ -2,7 +2,10 @@ use std::io::{Read, Seek, Write};
 
 use chrono::Local;
 
+#[cfg(not(test))]
 const LOG_PATH: &str = "log.txt";
+#[cfg(test)]
+const LOG_PATH: &str = "target/test_log.txt";
 
 pub fn log_append(msg: impl AsRef<str>) {
     if msg.as_ref().starts_with('\n') {
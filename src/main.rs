@@ -1,106 +1,122 @@
+mod bot;
+mod check;
 mod config;
+mod daemon;
 mod data;
+mod ddns;
+mod dns;
 mod email;
+mod error;
+mod ip_provider;
+mod ipv6;
 mod log;
+#[cfg(test)]
+mod mock_gateway;
+#[cfg(target_os = "linux")]
+mod netwatch;
+mod notify;
+mod outbox;
+mod policy;
+mod probe;
 mod task;
+mod template;
 mod utils;
+mod webhook;
 mod wlt;
 
+use std::process::ExitCode;
+
+use check::{
+    check_wlt, is_timeout, logout_wlt, new_wlt_client, notify_error, query_status, switch_wlt,
+};
 use config::Config;
+use daemon::{need_notify_timeout, run_daemon, run_watch};
 use data::Data;
-use email::send_email;
+use error::exit_code;
 use log::{log, log_append};
-use task::{query_task, set_task, unset_task};
-use utils::{
-    get_ipv6, get_range_u32, get_str_between, input_key_to_continue, print_list, replace_password,
-};
-use wlt::{WltClient, WltPageType};
-
-fn check_wlt() -> anyhow::Result<()> {
-    let config = Config::load()?;
-    let mut data = Data::load()?;
+use task::TaskSpec;
+use utils::{get_range_u32, input_key_to_continue, print_list, take_flag, take_option};
 
-    let mut wlt_client = WltClient::new(
-        &config.网络通用户名,
-        &config.网络通密码,
-        config.网络通出口,
-        config.网络通使用时限,
-        &data.rn,
-    )?;
-
-    let wlt_page = wlt_client.access_page()?;
-    let mut new_ipv4 = wlt_page.search_ip()?;
+fn run(profile: Option<&str>) -> anyhow::Result<()> {
+    let (config, profile) = Config::load_profile(profile)?;
+    let mut data = Data::load(profile.as_deref())?;
+    let mut wlt_client = new_wlt_client(&config, &data)?;
+    check_wlt(&config, &mut data, &mut wlt_client)
+}
 
-    let need_set_wlt = match wlt_page.page_type()? {
-        WltPageType::ControlPage => {
-            let type_text = get_str_between(&wlt_page.text, "出口: ", "网出口")?;
-            let type_ = type_text.as_bytes()[0] - b'1';
-            if type_ == config.网络通出口 {
-                false
-            } else {
-                log(format!("旧出口: {} 新出口: {}", type_, config.网络通出口));
-                true
-            }
-        }
-        WltPageType::LoginPage => {
-            wlt_client.login(&new_ipv4)?;
-            if wlt_client.get_rn() != data.rn {
-                log(format!("旧rn: {} 新rn: {}", data.rn, wlt_client.get_rn()));
-                data.rn = wlt_client.get_rn().to_owned();
-                data.save()?;
-            }
-            true
-        }
-    };
+fn logout(profile: Option<&str>) -> anyhow::Result<()> {
+    let (config, profile) = Config::load_profile(profile)?;
+    let mut data = Data::load(profile.as_deref())?;
+    let mut wlt_client = new_wlt_client(&config, &data)?;
+    logout_wlt(&mut data, &mut wlt_client)
+}
 
-    if need_set_wlt {
-        let set_wlt_page = wlt_client.set_wlt()?;
-        new_ipv4 = set_wlt_page.search_ip()?
+/// 打印网络通的当前状态，json为true时输出JSON
+fn status(profile: Option<&str>, json: bool) -> anyhow::Result<()> {
+    let (config, profile) = Config::load_profile(profile)?;
+    let data = Data::load(profile.as_deref())?;
+    let mut wlt_client = new_wlt_client(&config, &data)?;
+    let status = query_status(&mut wlt_client)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        println!("{}", status);
     }
+    Ok(())
+}
 
-    let old_ipv4 = data.ipv4.clone();
-    let old_ipv6 = data.ipv6.clone();
-    let new_ipv6 = match (config.检测IPv6, get_ipv6()) {
-        (true, Ok(new_ipv6)) => new_ipv6,
-        _ => old_ipv6.clone(),
+/// 按照命令行给出的出口和使用时限开通网络，save为true时同时保存到config.toml
+fn switch(profile: Option<&str>, exit: &str, exp: Option<&str>, save: bool) -> anyhow::Result<()> {
+    let (mut config, profile) = Config::load_profile(profile)?;
+    config.网络通出口 = match exit.parse() {
+        Ok(exit @ 0..=8) => exit,
+        _ => anyhow::bail!("出口应为0到8之间的数字: {}", exit),
     };
-    if new_ipv4 != old_ipv4 || new_ipv6 != old_ipv6 {
-        let body = config
-            .邮件内容
-            .replace("{旧IPv4}", &old_ipv4)
-            .replace("{旧IPv6}", &old_ipv6)
-            .replace("{新IPv4}", &new_ipv4)
-            .replace("{新IPv6}", &new_ipv6);
-        log(body.replace("\n", " "));
-        send_email(
-            &config.邮箱服务器,
-            &config.邮箱用户名,
-            &config.邮箱密码,
-            &config.邮件发送列表,
-            &config.邮件主题,
-            &body,
-        );
-        data.ipv4 = new_ipv4;
-        data.ipv6 = new_ipv6;
-        data.save()?;
+    if let Some(exp) = exp {
+        config.网络通使用时限 = exp
+            .parse()
+            .map_err(|_| anyhow::anyhow!("使用时限应为秒数: {}", exp))?;
+    }
+    let mut data = Data::load(profile.as_deref())?;
+    let mut wlt_client = new_wlt_client(&config, &data)?;
+    let info = switch_wlt(&mut data, &mut wlt_client)?;
+    println!("{}", info);
+    if save {
+        Config::save_exit(profile.as_deref(), config.网络通出口, config.网络通使用时限)?;
+        println!("已保存到config.toml");
     }
-
-    data.连续超时次数 = 0;
-    data.save()?;
-
-    log_append(".");
     Ok(())
 }
 
 const USAGE: &str = "usage:
     wlt_task             打开交互界面
-    wlt_task run         登录网络通，如果IP变化，发送邮件通知
+    wlt_task run         登录网络通，如果IP变化，发送邮件通知；出错时记录日志并通知，按照错误的种类返回非0的退出码
+    wlt_task logout      断开网络通连接，并清除保存的rn
+    wlt_task status      查看当前IP、出口、剩余时间（或到期时间）及是否已登录，不修改网络通的设置
+    wlt_task switch <exit>
+                         立即切换到指定出口（0到8，同config.toml中的网络通出口），未登录时先登录
+    wlt_task daemon      常驻运行，每隔一段时间（config.toml中的检测间隔）执行一次wlt_task run
+    wlt_task watch       前台运行，网络变化时执行一次wlt_task run（仅Linux可用），可以和计划任务一起使用
     wlt_task set         设置一个计划任务，每5分钟（或者网络连接的时候）执行一次wlt_task run
     wlt_task unset       取消这个计划任务
-    wlt_task query       查看计划任务状态";
+    wlt_task query       查看计划任务状态
+
+options:
+    --scheduler <name>   指定计划任务后端（windows、systemd、cron），默认根据当前系统自动选择
+    --exp <seconds>      switch使用的使用时限（同config.toml中的网络通使用时限），默认使用config.toml中的设置
+    --save               switch时同时把出口和使用时限保存到config.toml
+    --json               status以JSON格式输出
+    --profile <name>     使用config.toml中名为name的profile，默认使用config.toml中的默认profile；
+                         set时计划任务会加上profile的名字，每个profile可以分别设置计划任务";
 
-fn main() -> anyhow::Result<()> {
+/// 出错时返回错误的种类对应的退出码，wlt_task run出错时记录日志并通知，也返回退出码
+fn run_command() -> anyhow::Result<ExitCode> {
     let mut args: Vec<String> = std::env::args().collect();
+    let scheduler_name = take_option(&mut args, "--scheduler")?;
+    let profile = take_option(&mut args, "--profile")?;
+    let exp = take_option(&mut args, "--exp")?;
+    let save = take_flag(&mut args, "--save");
+    let json = take_flag(&mut args, "--json");
     let mut need_pause = false;
     if args.len() == 1 {
         need_pause = true;
@@ -124,43 +140,48 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    let mut code = ExitCode::SUCCESS;
     if args.len() == 2 && args[1] == "run" {
-        if let Err(e) = check_wlt() {
-            let mut e = e.to_string();
-            if let Ok(mut data) = Data::load() {
-                if e.contains("operation timed out") {
-                    data.连续超时次数 += 1;
-                    data.save()?;
-                    if data.连续超时次数 % 3 != 0 {
-                        log_append("?");
-                        return Ok(()); // timeout次数是3的倍数才通知
+        if let Err(e) = run(profile.as_deref()) {
+            code = ExitCode::from(exit_code(&e));
+            match Config::load_profile(profile.as_deref()) {
+                Ok((config, profile)) => {
+                    let mut data = Data::load(profile.as_deref())?;
+                    if is_timeout(&e) {
+                        data.连续超时次数 += 1;
+                        data.save()?;
+                        if !need_notify_timeout(data.连续超时次数) {
+                            log_append("?");
+                            return Ok(code);
+                        }
                     }
+                    notify_error(&config, &mut data, &e);
                 }
-            }
-
-            if let Ok(config) = Config::load() {
-                e = replace_password(e, config.网络通密码, "***");
-                log(&e);
-                send_email(
-                    &config.邮箱服务器,
-                    &config.邮箱用户名,
-                    &config.邮箱密码,
-                    &config.邮件发送列表,
-                    "网络通任务出错",
-                    &e,
-                );
-            } else {
-                log(&e);
+                Err(_) => log(e.to_string()),
             }
         }
+    } else if args.len() == 2 && args[1] == "logout" {
+        logout(profile.as_deref())?;
+    } else if args.len() == 2 && args[1] == "status" {
+        status(profile.as_deref(), json)?;
+    } else if args.len() == 3 && args[1] == "switch" {
+        switch(profile.as_deref(), &args[2], exp.as_deref(), save)?;
+    } else if args.len() == 2 && args[1] == "daemon" {
+        run_daemon(profile.as_deref())?;
+    } else if args.len() == 2 && args[1] == "watch" {
+        run_watch(profile.as_deref())?;
     } else if args.len() == 2 && (args[1] == "set" || args[1] == "unset" || args[1] == "query") {
-        let output = match args[1].as_str() {
-            "set" => set_task(),
-            "unset" => unset_task(),
-            "query" => query_task(),
+        // 只有用--profile指定时计划任务才带上profile，使用默认profile的计划任务在运行时读取默认profile
+        Config::load_profile(profile.as_deref())?;
+        let scheduler = task::scheduler(scheduler_name.as_deref())?;
+        let task = TaskSpec::current(profile.as_deref())?;
+        println!("计划任务后端: {}", scheduler.name());
+        match args[1].as_str() {
+            "set" => println!("{}", scheduler.install(&task)?),
+            "unset" => println!("{}", scheduler.uninstall(&task)?),
+            "query" => println!("{}", scheduler.status(&task)?),
             _ => unreachable!(),
-        }?;
-        println!("{}", output);
+        }
     } else {
         println!("{}", USAGE);
     }
@@ -168,5 +189,15 @@ fn main() -> anyhow::Result<()> {
     if need_pause {
         input_key_to_continue("", "\n按回车键退出...");
     }
-    Ok(())
+    Ok(code)
+}
+
+fn main() -> ExitCode {
+    match run_command() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
 }
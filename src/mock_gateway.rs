@@ -0,0 +1,362 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use encoding_rs::GBK;
+
+use crate::wlt::EXIT_NAMES;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// 包含查询字符串
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    s.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| {
+                let s = s.replace('+', " ");
+                String::from_utf8_lossy(&urlencoding::decode_binary(s.as_bytes())).to_string()
+            };
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 查询字符串和表单中的参数
+    pub fn param(&self, name: &str) -> Option<String> {
+        let query = self.target.split_once('?').map_or("", |(_, q)| q);
+        parse_params(query)
+            .into_iter()
+            .chain(parse_params(&self.body))
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.header("cookie")?
+            .split(';')
+            .filter_map(|kv| kv.trim().split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.to_owned())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// 发送时会被编码为GBK
+    pub body: String,
+    /// 发送响应前等待的时间，用于模拟超时
+    pub delay: Duration,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<String>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: body.into(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+/// 在本机随机端口上运行的HTTP服务器，drop时停止
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    stop: Arc<AtomicBool>,
+    addr: std::net::SocketAddr,
+}
+
+impl MockServer {
+    /// path为服务的路径，如"/cgi-bin/ip"，url会包含这个路径
+    pub fn start(
+        path: &str,
+        handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler: Arc<Handler> = Arc::new(handler);
+        {
+            let requests = requests.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else { continue };
+                    let requests = requests.clone();
+                    let handler = handler.clone();
+                    std::thread::spawn(move || {
+                        let _ = serve(stream, &requests, handler.as_ref());
+                    });
+                }
+            });
+        }
+        Self {
+            url: format!("http://{}{}", addr, path),
+            requests,
+            stop,
+            addr,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    requests: &Mutex<Vec<Request>>,
+    handler: &Handler,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default().to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_owned(), v.trim().to_owned()));
+        }
+    }
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let request = Request {
+        method,
+        target,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    };
+    requests.lock().unwrap().push(request.clone());
+    let response = handler(&request);
+    std::thread::sleep(response.delay);
+
+    let body = GBK.encode(&response.body).0;
+    let mut head = format!(
+        "HTTP/1.1 {} MOCK\r\nContent-Type: text/html; charset=GBK\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        body.len()
+    );
+    for (k, v) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    head.push_str("\r\n");
+    let mut stream = stream;
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+pub fn login_page(ip: &str, message: &str) -> String {
+    format!(
+        r#"<html><head><meta http-equiv="Content-Type" content="text/html; charset=gb2312"><title>中国科大网络通</title></head>
+<body>
+<form method=post action=/cgi-bin/ip>
+<p>网络通账号登录</p>
+<p>{message}</p>
+<input type=hidden name=cmd value=login>
+<input type=hidden name=url value=URL>
+<input type=hidden name=ip value={ip}>
+<p>用户名 <input type=text name=name size=16></p>
+<p>密码 <input type=password name=password size=16></p>
+<p><input type=checkbox name=savepass checked>记住密码</p>
+<p><input type=submit name=go value="登录账户"></p>
+</form>
+</body></html>
+"#
+    )
+}
+
+pub fn control_page(ip: &str, name: &str, exit: u8, exp: u32, message: &str) -> String {
+    let exit_name = EXIT_NAMES[exit as usize];
+    let exit_number = exit + 1;
+    let exp_text = match exp {
+        0 => "永久".to_owned(),
+        exp => format!("{}小时", exp / 3600),
+    };
+    let remaining = match exp {
+        0 => String::new(),
+        _ => format!("<tr><td>剩余时间</td><td>{exp_text}</td></tr>"),
+    };
+    let message = match message {
+        "" => String::new(),
+        message => format!("<tr><td colspan=2><font color=red>{message}</font></td></tr>"),
+    };
+    format!(
+        r#"<html><head><meta http-equiv="Content-Type" content="text/html; charset=gb2312"><title>中国科大网络通</title></head>
+<body>
+<table>
+<tr><td>当前IP地址</td><td>{ip}</td></tr>
+<tr><td>网络通账号</td><td>{name}</td></tr>
+<tr><td>状态</td><td>出口: {exit_number}{exit_name}，权限: 国际，使用时限: {exp_text}</td></tr>
+{remaining}
+{message}
+</table>
+<p>访问文献资源建议使用1出口</p>
+<p><a href="/cgi-bin/ip?cmd=logout">退出网络通</a></p>
+</body></html>
+"#
+    )
+}
+
+/// 模拟网关的状态
+#[derive(Debug, Clone)]
+pub struct GatewayState {
+    pub ip: String,
+    pub name: String,
+    pub password: String,
+    pub rn: String,
+    pub logged_in: bool,
+    pub exit: u8,
+    pub exp: u32,
+}
+
+impl Default for GatewayState {
+    fn default() -> Self {
+        Self {
+            ip: "114.214.180.23".to_owned(),
+            name: "alice".to_owned(),
+            password: "p@ss word".to_owned(),
+            rn: "1a2b3c4d".to_owned(),
+            logged_in: false,
+            exit: 0,
+            exp: 0,
+        }
+    }
+}
+
+/// 按照网络通的行为响应请求：未登录时返回登录页面，登录后返回控制页面，cmd=set时设置出口，cmd=logout时断开连接
+pub fn wlt_handler(state: &Mutex<GatewayState>, request: &Request) -> Response {
+    let mut state = state.lock().unwrap();
+    let cmd = request.param("cmd").unwrap_or_default();
+    let authorized = state.logged_in && request.cookie("rn").as_deref() == Some(&state.rn);
+    match cmd.as_str() {
+        "login" => {
+            let name = request.param("name").unwrap_or_default();
+            let password = request.param("password").unwrap_or_default();
+            if name != state.name {
+                Response::ok(login_page(&state.ip, "用户名不存在"))
+            } else if password != state.password {
+                Response::ok(login_page(&state.ip, "用户名或密码错误"))
+            } else {
+                state.logged_in = true;
+                let rn_cookie = format!("rn={}", state.rn);
+                Response::ok(control_page(
+                    &state.ip,
+                    &state.name,
+                    state.exit,
+                    state.exp,
+                    "",
+                ))
+                .header("Set-Cookie", &rn_cookie)
+            }
+        }
+        "set" if authorized => {
+            state.exit = request.param("type").unwrap().parse().unwrap();
+            state.exp = request.param("exp").unwrap().parse().unwrap();
+            Response::ok(control_page(
+                &state.ip,
+                &state.name,
+                state.exit,
+                state.exp,
+                "信息：网络设置成功",
+            ))
+        }
+        "logout" => {
+            state.logged_in = false;
+            Response::ok(login_page(&state.ip, ""))
+        }
+        _ if authorized => Response::ok(control_page(
+            &state.ip,
+            &state.name,
+            state.exit,
+            state.exp,
+            "",
+        )),
+        _ => Response::ok(login_page(&state.ip, "")),
+    }
+}
+
+/// 启动按照网络通行为响应的模拟网关
+pub fn start_wlt(state: GatewayState) -> (MockServer, Arc<Mutex<GatewayState>>) {
+    let state = Arc::new(Mutex::new(state));
+    let server = {
+        let state = state.clone();
+        MockServer::start("/cgi-bin/ip", move |request| wlt_handler(&state, request))
+    };
+    (server, state)
+}
+
+/// 返回一个没有监听的本机地址，连接会被拒绝
+pub fn closed_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    format!("http://{}/cgi-bin/ip", addr)
+}
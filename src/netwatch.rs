@@ -0,0 +1,298 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::{Duration, Instant},
+};
+
+use crate::log::log;
+
+/// 网络安静这么久之后才触发检测，避免一次网络变化产生的一连串事件触发多次检测
+const QUIET_TIME: Duration = Duration::from_secs(3);
+/// 两次触发之间的最短间隔，避免反复断开连接的网卡频繁访问网络通
+const MIN_GAP: Duration = Duration::from_secs(30);
+
+/// 网络变化事件的去抖
+pub struct Debouncer {
+    quiet: Duration,
+    min_gap: Duration,
+    last_event: Option<Instant>,
+    last_fire: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration, min_gap: Duration) -> Self {
+        Self {
+            quiet,
+            min_gap,
+            last_event: None,
+            last_fire: None,
+        }
+    }
+
+    pub fn on_event(&mut self, now: Instant) {
+        self.last_event = Some(now);
+    }
+
+    /// 有待触发的事件时，返回应当触发的时间
+    pub fn deadline(&self) -> Option<Instant> {
+        let quiet_deadline = self.last_event? + self.quiet;
+        match self.last_fire {
+            Some(last_fire) => Some(quiet_deadline.max(last_fire + self.min_gap)),
+            None => Some(quiet_deadline),
+        }
+    }
+
+    pub fn fire_if_due(&mut self, now: Instant) -> bool {
+        match self.deadline() {
+            Some(deadline) if deadline <= now => {
+                self.last_event = None;
+                self.last_fire = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+const NLMSG_HDRLEN: usize = 16;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// 判断一次收到的netlink消息中是否有新地址或网卡连接上的事件
+pub fn has_network_change(buf: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= buf.len() {
+        let len = read_u32(buf, offset) as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            break;
+        }
+        let payload = &buf[offset + NLMSG_HDRLEN..offset + len];
+        match read_u16(buf, offset + 4) {
+            libc::RTM_NEWADDR => return true,
+            // struct ifinfomsg { family: u8, pad: u8, type: u16, index: i32, flags: u32, change: u32 }
+            libc::RTM_NEWLINK if payload.len() >= 12 => {
+                let flags = read_u32(payload, 8);
+                let up = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+                if flags & up == up {
+                    return true;
+                }
+            }
+            _ => (),
+        }
+        offset += (len + 3) & !3;
+    }
+    false
+}
+
+struct NetlinkSocket {
+    fd: OwnedFd,
+}
+
+impl NetlinkSocket {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+
+    /// timeout为None时一直等待
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.map(|t| t.max(Duration::from_millis(1)));
+        let tv = libc::timeval {
+            tv_sec: timeout.map_or(0, |t| t.as_secs() as libc::time_t),
+            tv_usec: timeout.map_or(0, |t| t.subsec_micros() as libc::suseconds_t),
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &tv as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+/// 在后台线程中监听rtnetlink的地址及网卡事件，网络变化（去抖后）时调用on_change
+pub fn spawn_watcher(on_change: impl Fn() + Send + 'static) -> anyhow::Result<()> {
+    let socket = NetlinkSocket::open()?;
+    std::thread::spawn(move || {
+        let mut debouncer = Debouncer::new(QUIET_TIME, MIN_GAP);
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let timeout = debouncer
+                .deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Err(e) = socket.set_timeout(timeout) {
+                log(format!("监听网络变化失败: {}", e));
+                return;
+            }
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if has_network_change(&buf[..len]) {
+                        debouncer.on_event(Instant::now());
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                // 事件太多导致缓冲区溢出时，丢失的事件中可能有网络变化
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    debouncer.on_event(Instant::now());
+                }
+                Err(e) => {
+                    log(format!("监听网络变化失败: {}", e));
+                    return;
+                }
+            }
+            if debouncer.fire_if_due(Instant::now()) {
+                on_change();
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn debouncer_waits_for_quiet() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(3 * SECOND, 30 * SECOND);
+        assert_eq!(debouncer.deadline(), None);
+        assert!(!debouncer.fire_if_due(start));
+
+        debouncer.on_event(start);
+        debouncer.on_event(start + 2 * SECOND);
+        assert_eq!(debouncer.deadline(), Some(start + 5 * SECOND));
+        assert!(!debouncer.fire_if_due(start + 4 * SECOND));
+        assert!(debouncer.fire_if_due(start + 5 * SECOND));
+        assert_eq!(debouncer.deadline(), None);
+        assert!(!debouncer.fire_if_due(start + 6 * SECOND));
+    }
+
+    #[test]
+    fn debouncer_keeps_min_gap() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(3 * SECOND, 30 * SECOND);
+        debouncer.on_event(start);
+        assert!(debouncer.fire_if_due(start + 3 * SECOND));
+
+        // 网卡反复断开连接时，两次触发至少间隔min_gap，期间的事件合并成一次
+        debouncer.on_event(start + 10 * SECOND);
+        debouncer.on_event(start + 20 * SECOND);
+        assert_eq!(debouncer.deadline(), Some(start + 33 * SECOND));
+        assert!(!debouncer.fire_if_due(start + 23 * SECOND));
+        assert!(debouncer.fire_if_due(start + 33 * SECOND));
+        assert_eq!(debouncer.deadline(), None);
+    }
+
+    /// 构造一条netlink消息，payload按4字节对齐
+    fn message(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let len = NLMSG_HDRLEN + payload.len();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.extend_from_slice(&[0; 10]);
+        buf.extend_from_slice(payload);
+        buf.resize((len + 3) & !3, 0);
+        buf
+    }
+
+    fn link_message(flags: u32) -> Vec<u8> {
+        let mut ifinfomsg = vec![0; 8];
+        ifinfomsg.extend_from_slice(&flags.to_ne_bytes());
+        ifinfomsg.extend_from_slice(&0u32.to_ne_bytes());
+        message(libc::RTM_NEWLINK, &ifinfomsg)
+    }
+
+    #[test]
+    fn detects_new_address_and_link_up() {
+        assert!(has_network_change(&message(libc::RTM_NEWADDR, &[0; 8])));
+        let up = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+        assert!(has_network_change(&link_message(up)));
+        assert!(!has_network_change(&link_message(libc::IFF_UP as u32)));
+        assert!(!has_network_change(&message(libc::RTM_DELADDR, &[0; 8])));
+        assert!(!has_network_change(&[]));
+
+        // 同一次收到的多条消息中后面的消息有变化
+        let mut buf = message(libc::RTM_DELADDR, &[0; 5]);
+        buf.extend(link_message(up));
+        assert!(has_network_change(&buf));
+    }
+
+    #[test]
+    fn ignores_truncated_messages() {
+        let mut buf = message(libc::RTM_NEWADDR, &[0; 8]);
+        buf.truncate(NLMSG_HDRLEN + 4);
+        assert!(!has_network_change(&buf));
+        // 长度小于消息头时停止解析
+        let mut buf = message(libc::RTM_NEWADDR, &[0; 8]);
+        buf[..4].copy_from_slice(&4u32.to_ne_bytes());
+        assert!(!has_network_change(&buf));
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bot::{DingTalk, Feishu, ServerChan, Telegram};
+use crate::config::Config;
+use crate::data::Data;
+use crate::email::EmailNotifier;
+use crate::log::log;
+use crate::outbox;
+use crate::template;
+use crate::utils::{hostname, split_header};
+use crate::webhook::Webhook;
+
+/// 需要通知的事件，发送失败时和通知内容一起保存在发件箱中
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Event {
+    IpChanged {
+        old_ipv4: String,
+        new_ipv4: String,
+        old_ipv6: String,
+        new_ipv6: String,
+    },
+    Error {
+        error: String,
+    },
+    /// 出错后检测恢复正常，error为之前的错误
+    Recovered {
+        error: String,
+    },
+    ExitChanged {
+        old_exit: u8,
+        new_exit: u8,
+        reason: String,
+    },
+    LoginFailed {
+        error: String,
+    },
+}
+
+/// 事件的种类，用于通知渠道订阅
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    #[serde(rename = "IP变化")]
+    IpChanged,
+    #[serde(rename = "出错")]
+    Error,
+    #[serde(rename = "恢复")]
+    Recovered,
+    #[serde(rename = "出口变化")]
+    ExitChanged,
+    #[serde(rename = "登录失败")]
+    LoginFailed,
+}
+
+impl EventKind {
+    /// 中文名，同config.toml中的写法
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::IpChanged => "IP变化",
+            EventKind::Error => "出错",
+            EventKind::Recovered => "恢复",
+            EventKind::ExitChanged => "出口变化",
+            EventKind::LoginFailed => "登录失败",
+        }
+    }
+
+    /// 英文名，用于Webhook和命令的环境变量
+    pub fn id(&self) -> &'static str {
+        match self {
+            EventKind::IpChanged => "ip_changed",
+            EventKind::Error => "error",
+            EventKind::Recovered => "recovered",
+            EventKind::ExitChanged => "exit_changed",
+            EventKind::LoginFailed => "login_failed",
+        }
+    }
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::IpChanged { .. } => EventKind::IpChanged,
+            Event::Error { .. } => EventKind::Error,
+            Event::Recovered { .. } => EventKind::Recovered,
+            Event::ExitChanged { .. } => EventKind::ExitChanged,
+            Event::LoginFailed { .. } => EventKind::LoginFailed,
+        }
+    }
+}
+
+/// 按照模板生成的通知内容
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub title: String,
+    pub text: String,
+    /// 通知模板中设置了HTML内容时才有，只用于邮件
+    pub html: Option<String>,
+}
+
+/// 通知渠道
+pub trait Notifier {
+    fn notify(&self, event: &Event, message: &Message) -> anyhow::Result<()>;
+}
+
+/// 运行本地命令，事件的内容通过环境变量传递，命令返回非0或超时时认为通知失败
+pub struct CommandNotifier<'a> {
+    pub command: &'a str,
+    pub args: &'a [String],
+    pub timeout: Duration,
+}
+
+impl Notifier for CommandNotifier<'_> {
+    fn notify(&self, event: &Event, message: &Message) -> anyhow::Result<()> {
+        let mut command = Command::new(self.command);
+        command
+            .args(self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .env("WLT_EVENT", event.kind().id())
+            .env("WLT_HOSTNAME", hostname())
+            .env("WLT_TITLE", &message.title)
+            .env("WLT_MESSAGE", &message.text);
+        match event {
+            Event::IpChanged {
+                old_ipv4,
+                new_ipv4,
+                old_ipv6,
+                new_ipv6,
+            } => {
+                command
+                    .env("WLT_OLD_IPV4", old_ipv4)
+                    .env("WLT_NEW_IPV4", new_ipv4)
+                    .env("WLT_OLD_IPV6", old_ipv6)
+                    .env("WLT_NEW_IPV6", new_ipv6);
+            }
+            Event::ExitChanged {
+                old_exit, new_exit, ..
+            } => {
+                command
+                    .env("WLT_OLD_EXIT", old_exit.to_string())
+                    .env("WLT_NEW_EXIT", new_exit.to_string());
+            }
+            Event::Error { error } | Event::Recovered { error } | Event::LoginFailed { error } => {
+                command.env("WLT_ERROR", error);
+            }
+        }
+        let mut child = command.spawn()?;
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                if !status.success() {
+                    anyhow::bail!("命令{}返回{}", self.command, status);
+                }
+                return Ok(());
+            }
+            if start.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("命令{}运行超时", self.command);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChannelType {
+    #[serde(rename = "邮件")]
+    Email,
+    Webhook,
+    Telegram,
+    #[serde(rename = "Server酱")]
+    ServerChan,
+    #[serde(rename = "钉钉")]
+    DingTalk,
+    #[serde(rename = "飞书")]
+    Feishu,
+    #[serde(rename = "命令")]
+    Command,
+}
+
+impl Display for ChannelType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelType::Email => write!(f, "邮件"),
+            ChannelType::Webhook => write!(f, "Webhook"),
+            ChannelType::Telegram => write!(f, "Telegram"),
+            ChannelType::ServerChan => write!(f, "Server酱"),
+            ChannelType::DingTalk => write!(f, "钉钉"),
+            ChannelType::Feishu => write!(f, "飞书"),
+            ChannelType::Command => write!(f, "命令"),
+        }
+    }
+}
+
+/// config.toml中"通知渠道"的一项
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NotifyChannel {
+    pub 类型: ChannelType,
+    /// 订阅的事件，为空时订阅所有事件
+    pub 事件: Vec<EventKind>,
+    /// Webhook、钉钉、飞书的机器人地址，Telegram、Server酱的接口地址（为空时使用官方地址）
+    pub 地址: String,
+    /// Telegram的机器人令牌或Server酱的SendKey
+    pub 令牌: String,
+    pub 聊天ID: String,
+    /// Webhook、钉钉、飞书的签名密钥，为空时不签名
+    pub 签名密钥: String,
+    pub 请求头: Vec<String>,
+    pub 命令: String,
+    pub 参数: Vec<String>,
+    pub 重试次数: u32,
+    pub 重试间隔: u64,
+    pub 超时: u64,
+}
+
+impl Default for NotifyChannel {
+    fn default() -> Self {
+        let webhook = Webhook::default();
+        Self {
+            类型: ChannelType::Email,
+            事件: Vec::new(),
+            地址: String::new(),
+            令牌: String::new(),
+            聊天ID: String::new(),
+            签名密钥: String::new(),
+            请求头: Vec::new(),
+            命令: String::new(),
+            参数: Vec::new(),
+            重试次数: webhook.重试次数,
+            重试间隔: webhook.重试间隔,
+            超时: webhook.超时,
+        }
+    }
+}
+
+impl From<&Webhook> for NotifyChannel {
+    fn from(webhook: &Webhook) -> Self {
+        Self {
+            类型: ChannelType::Webhook,
+            地址: webhook.地址.clone(),
+            请求头: webhook.请求头.clone(),
+            签名密钥: webhook.签名密钥.clone(),
+            重试次数: webhook.重试次数,
+            重试间隔: webhook.重试间隔,
+            超时: webhook.超时,
+            ..Default::default()
+        }
+    }
+}
+
+impl NotifyChannel {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let missing = match self.类型 {
+            ChannelType::Email => None,
+            ChannelType::Webhook | ChannelType::DingTalk | ChannelType::Feishu => {
+                Some("地址").filter(|_| self.地址.trim().is_empty())
+            }
+            ChannelType::Telegram if self.聊天ID.trim().is_empty() => Some("聊天ID"),
+            ChannelType::Telegram | ChannelType::ServerChan => {
+                Some("令牌").filter(|_| self.令牌.trim().is_empty())
+            }
+            ChannelType::Command => Some("命令").filter(|_| self.命令.trim().is_empty()),
+        };
+        if let Some(field) = missing {
+            anyhow::bail!("通知渠道中需要填写{}", field);
+        }
+        for header in self.请求头.iter() {
+            split_header(header)?;
+        }
+        Ok(())
+    }
+
+    /// 用于在发件箱中找到补发的渠道，为类型加上地址、聊天ID和命令的哈希：
+    /// Telegram及Server酱的地址中包含令牌，发件箱中不保存原文
+    pub fn key(&self) -> String {
+        let mut hasher = Sha256::new();
+        for s in [&self.地址, &self.聊天ID, &self.命令] {
+            hasher.update(s.as_bytes());
+            hasher.update([0]);
+        }
+        format!("{} {}", self.类型, hex::encode(&hasher.finalize()[..8]))
+    }
+
+    /// 邮件发送列表为空时不发送邮件，也不保存到发件箱
+    pub fn enabled(&self, config: &Config) -> bool {
+        self.类型 != ChannelType::Email || !config.邮件发送列表.is_empty()
+    }
+
+    pub fn subscribes(&self, kind: EventKind) -> bool {
+        self.事件.is_empty() || self.事件.contains(&kind)
+    }
+
+    /// 发送HTTP请求时使用，负责重试
+    pub fn webhook(&self, url: &str) -> Webhook {
+        Webhook {
+            地址: url.to_owned(),
+            请求头: self.请求头.clone(),
+            签名密钥: String::new(),
+            重试次数: self.重试次数,
+            重试间隔: self.重试间隔,
+            超时: self.超时,
+        }
+    }
+
+    pub fn notifier<'a>(&'a self, config: &'a Config) -> Box<dyn Notifier + 'a> {
+        match self.类型 {
+            ChannelType::Email => Box::new(EmailNotifier { config }),
+            ChannelType::Webhook => Box::new(Webhook {
+                签名密钥: self.签名密钥.clone(),
+                ..self.webhook(&self.地址)
+            }),
+            ChannelType::Telegram => Box::new(Telegram { channel: self }),
+            ChannelType::ServerChan => Box::new(ServerChan { channel: self }),
+            ChannelType::DingTalk => Box::new(DingTalk { channel: self }),
+            ChannelType::Feishu => Box::new(Feishu { channel: self }),
+            ChannelType::Command => Box::new(CommandNotifier {
+                command: &self.命令,
+                args: &self.参数,
+                timeout: Duration::from_millis(self.超时),
+            }),
+        }
+    }
+}
+
+/// 没有设置"通知渠道"时，和以前一样用邮件和"Webhook"通知IP变化和出错
+pub fn channels(config: &Config) -> Cow<'_, [NotifyChannel]> {
+    if !config.通知渠道.is_empty() {
+        return Cow::Borrowed(&config.通知渠道);
+    }
+    let events = vec![EventKind::IpChanged, EventKind::Error, EventKind::Recovered];
+    let mut channels = vec![NotifyChannel {
+        事件: events.clone(),
+        ..Default::default()
+    }];
+    channels.extend(config.Webhook.iter().map(|webhook| NotifyChannel {
+        事件: events.clone(),
+        ..webhook.into()
+    }));
+    Cow::Owned(channels)
+}
+
+/// 通过订阅了这个事件的所有渠道通知，失败时记录日志并保存到发件箱，之后检测成功时补发
+pub fn notify(config: &Config, data: &Data, event: &Event) {
+    let message = template::message(config, data, event);
+    for channel in channels(config).iter() {
+        if !channel.enabled(config) || !channel.subscribes(event.kind()) {
+            continue;
+        }
+        if let Err(e) = channel.notifier(config).notify(event, &message) {
+            log(format!("{}通知失败: {:#}", channel.类型, e));
+            let now = Local::now().naive_local();
+            if let Err(e) = outbox::enqueue(config, data, channel, event, &message, &e, now) {
+                log(format!("保存到发件箱失败: {:#}", e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::mock_gateway::{MockServer, Response};
+
+    pub fn test_message() -> Message {
+        Message {
+            title: "标题".to_owned(),
+            text: "内容".to_owned(),
+            html: None,
+        }
+    }
+
+    fn error_event() -> Event {
+        Event::Error {
+            error: "连接失败".to_owned(),
+        }
+    }
+
+    #[test]
+    fn dispatches_to_subscribed_channels() {
+        let all = MockServer::start("/", |_| Response::ok(""));
+        let ip_only = MockServer::start("/", |_| Response::ok(""));
+        let config = Config {
+            通知渠道: vec![
+                NotifyChannel {
+                    类型: ChannelType::Webhook,
+                    地址: all.url.clone(),
+                    ..Default::default()
+                },
+                NotifyChannel {
+                    类型: ChannelType::Webhook,
+                    事件: vec![EventKind::IpChanged],
+                    地址: ip_only.url.clone(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        notify(&config, &Data::default(), &error_event());
+        notify(
+            &config,
+            &Data::default(),
+            &Event::Recovered {
+                error: "连接失败".to_owned(),
+            },
+        );
+        assert_eq!(all.requests().len(), 2);
+        assert_eq!(ip_only.requests().len(), 0);
+        let json: serde_json::Value = serde_json::from_str(&all.requests()[1].body).unwrap();
+        assert_eq!(json["event"], "recovered");
+        assert_eq!(json["title"], "网络通任务恢复正常");
+    }
+
+    #[test]
+    fn legacy_settings_become_channels() {
+        let config = Config {
+            Webhook: vec![Webhook {
+                地址: "http://127.0.0.1/".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let channels = channels(&config);
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].类型, ChannelType::Email);
+        assert!(!channels[0].enabled(&config));
+        assert!(channels[1].enabled(&config));
+        assert_eq!(channels[1].类型, ChannelType::Webhook);
+        assert!(channels[1].key().starts_with("Webhook "));
+        assert!(!channels[1].key().contains("127.0.0.1"));
+        assert!(channels[1].subscribes(EventKind::Error));
+        assert!(channels[1].subscribes(EventKind::Recovered));
+        assert!(!channels[1].subscribes(EventKind::ExitChanged));
+    }
+
+    #[test]
+    fn channel_validation() {
+        let source: NotifyChannel = toml::from_str(
+            r#"
+"类型" = "Telegram"
+"事件" = ["IP变化", "恢复"]
+"令牌" = "123:abc"
+"#,
+        )
+        .unwrap();
+        assert_eq!(source.事件, [EventKind::IpChanged, EventKind::Recovered]);
+        assert!(source.validate().is_err());
+        assert!(NotifyChannel::default().validate().is_ok());
+        let command = NotifyChannel {
+            类型: ChannelType::Command,
+            ..Default::default()
+        };
+        assert!(command.validate().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_receives_event_in_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let args = vec![
+            "-c".to_owned(),
+            format!("echo \"$WLT_EVENT $WLT_ERROR\" > {}", out.display()),
+        ];
+        let notifier = CommandNotifier {
+            command: "sh",
+            args: &args,
+            timeout: Duration::from_secs(5),
+        };
+        notifier.notify(&error_event(), &test_message()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "error 连接失败\n");
+
+        let args = vec!["-c".to_owned(), "exit 3".to_owned()];
+        let notifier = CommandNotifier {
+            command: "sh",
+            args: &args,
+            timeout: Duration::from_secs(5),
+        };
+        assert!(notifier.notify(&error_event(), &test_message()).is_err());
+
+        let args = vec!["-c".to_owned(), "sleep 5".to_owned()];
+        let notifier = CommandNotifier {
+            command: "sh",
+            args: &args,
+            timeout: Duration::from_millis(200),
+        };
+        let e = notifier
+            .notify(&error_event(), &test_message())
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "命令sh运行超时");
+    }
+}
@@ -0,0 +1,371 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::data::{Data, TIME_FORMAT};
+use crate::log::log;
+use crate::notify::{self, Event, Message, NotifyChannel};
+
+const OUTBOX_COMMENT: &str = r#"
+# 发送失败的通知，之后每次检测成功时补发，发送成功后删除
+# 渠道：通知渠道的类型，以及地址等的哈希（地址中可能有令牌，不保存原文），config.toml中没有这个渠道时放弃补发
+# 创建时间：第一次发送失败的时间，超过"补发期限"后放弃补发
+# 尝试次数：已经发送失败的次数，每次失败后等待的时间翻倍，从"补发间隔"到"最大补发间隔"
+# 下次尝试时间：在这个时间之后的检测中补发
+# 错误：上次发送失败的原因
+"#;
+
+/// 发件箱中的一条通知
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingNotification {
+    pub 渠道: String,
+    pub 创建时间: String,
+    pub 尝试次数: u32,
+    pub 下次尝试时间: String,
+    pub 错误: String,
+    pub 主题: String,
+    pub 内容: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub HTML内容: Option<String>,
+    pub 事件: Event,
+}
+
+impl PendingNotification {
+    fn message(&self) -> Message {
+        Message {
+            title: self.主题.clone(),
+            text: self.内容.clone(),
+            html: self.HTML内容.clone(),
+        }
+    }
+
+    /// 失败一次，按照尝试次数计算下次尝试时间
+    fn fail(&mut self, config: &Config, e: &anyhow::Error, now: NaiveDateTime) {
+        self.尝试次数 += 1;
+        self.错误 = format!("{:#}", e);
+        self.下次尝试时间 = (now + backoff(config, self.尝试次数))
+            .format(TIME_FORMAT)
+            .to_string();
+    }
+
+    /// 下次尝试时间无法解析时立即重试
+    fn is_due(&self, now: NaiveDateTime) -> bool {
+        NaiveDateTime::parse_from_str(&self.下次尝试时间, TIME_FORMAT)
+            .map_or(true, |time| time <= now)
+    }
+
+    /// 创建时间超过补发期限，创建时间无法解析时也视为过期
+    fn is_expired(&self, config: &Config, now: NaiveDateTime) -> bool {
+        NaiveDateTime::parse_from_str(&self.创建时间, TIME_FORMAT).map_or(true, |time| {
+            now - time > Duration::seconds(config.补发期限 as i64)
+        })
+    }
+}
+
+/// 第attempts次失败后等待的时间，从补发间隔开始每次翻倍，不超过最大补发间隔
+fn backoff(config: &Config, attempts: u32) -> Duration {
+    let factor = 1u64 << attempts.saturating_sub(1).min(20);
+    let seconds = config
+        .补发间隔
+        .saturating_mul(factor)
+        .min(config.最大补发间隔.max(config.补发间隔));
+    Duration::seconds(seconds as i64)
+}
+
+/// 发送失败、等待补发的通知，保存在outbox.toml中
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Outbox {
+    pub 通知: Vec<PendingNotification>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Outbox {
+    /// 文件不存在时返回空的发件箱
+    pub fn load_from(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut outbox = match path.exists() {
+            true => toml::from_str::<Outbox>(&std::fs::read_to_string(path)?)?,
+            false => Outbox::default(),
+        };
+        outbox.path = path.to_owned();
+        Ok(outbox)
+    }
+
+    /// 发件箱为空时删除文件
+    pub fn save(&self) -> anyhow::Result<()> {
+        if self.通知.is_empty() {
+            if self.path.exists() {
+                std::fs::remove_file(&self.path)?;
+            }
+            return Ok(());
+        }
+        let outbox_string = toml::to_string_pretty(self)?;
+        let content = format!("{}\n{}", outbox_string, OUTBOX_COMMENT);
+        std::fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+/// 把发送失败的通知保存到data所在目录的发件箱中
+pub fn enqueue(
+    config: &Config,
+    data: &Data,
+    channel: &NotifyChannel,
+    event: &Event,
+    message: &Message,
+    e: &anyhow::Error,
+    now: NaiveDateTime,
+) -> anyhow::Result<()> {
+    let path = data
+        .outbox_path()
+        .ok_or(anyhow::anyhow!("数据文件的名字不是data*.toml"))?;
+    let mut outbox = Outbox::load_from(path)?;
+    let mut notification = PendingNotification {
+        渠道: channel.key(),
+        创建时间: now.format(TIME_FORMAT).to_string(),
+        尝试次数: 0,
+        下次尝试时间: String::new(),
+        错误: String::new(),
+        主题: message.title.clone(),
+        内容: message.text.clone(),
+        HTML内容: message.html.clone(),
+        事件: event.clone(),
+    };
+    notification.fail(config, e, now);
+    outbox.通知.push(notification);
+    outbox.save()
+}
+
+/// 补发发件箱中到了下次尝试时间的通知，成功后从发件箱中删除，失败时推迟下次尝试时间，超过补发期限的通知直接删除
+pub fn flush(config: &Config, data: &Data, now: NaiveDateTime) -> anyhow::Result<()> {
+    let Some(path) = data.outbox_path().filter(|path| path.exists()) else {
+        return Ok(());
+    };
+    let mut outbox = Outbox::load_from(path)?;
+    let channels = notify::channels(config);
+    let mut changed = false;
+    outbox.通知.retain_mut(|notification| {
+        if notification.is_expired(config, now) {
+            log(format!(
+                "通知超过{}秒仍未发送成功，放弃补发: {}（{}）",
+                config.补发期限, notification.主题, notification.创建时间
+            ));
+            changed = true;
+            return false;
+        }
+        if !notification.is_due(now) {
+            return true;
+        }
+        changed = true;
+        let channel = channels
+            .iter()
+            .find(|channel| channel.key() == notification.渠道 && channel.enabled(config));
+        let Some(channel) = channel else {
+            log(format!(
+                "没有通知渠道{}，放弃补发通知: {}",
+                notification.渠道, notification.主题
+            ));
+            return false;
+        };
+        let result = channel
+            .notifier(config)
+            .notify(&notification.事件, &notification.message());
+        match result {
+            Ok(()) => {
+                log(format!(
+                    "补发{}通知成功: {}（{}）",
+                    channel.类型, notification.主题, notification.创建时间
+                ));
+                false
+            }
+            Err(e) => {
+                log(format!("补发{}通知失败: {:#}", channel.类型, e));
+                notification.fail(config, &e, now);
+                true
+            }
+        }
+    });
+    if changed {
+        outbox.save()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::mock_gateway::{MockServer, Response};
+    use crate::notify::tests::test_message;
+    use crate::notify::{notify, ChannelType};
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, TIME_FORMAT).unwrap()
+    }
+
+    /// 返回的开关为true时服务器返回200，否则返回400（不在Webhook内部重试）
+    fn start_flaky_server() -> (MockServer, Arc<AtomicBool>) {
+        let up = Arc::new(AtomicBool::new(false));
+        let state = up.clone();
+        let server = MockServer::start("/", move |_| match state.load(Ordering::SeqCst) {
+            true => Response::ok(""),
+            false => Response::ok("").status(400),
+        });
+        (server, up)
+    }
+
+    fn webhook_config(url: &str) -> Config {
+        Config {
+            通知渠道: vec![NotifyChannel {
+                类型: ChannelType::Webhook,
+                地址: url.to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn ip_changed() -> Event {
+        Event::IpChanged {
+            old_ipv4: "1.1.1.1".to_owned(),
+            new_ipv4: "2.2.2.2".to_owned(),
+            old_ipv6: String::new(),
+            new_ipv6: String::new(),
+        }
+    }
+
+    #[test]
+    fn failed_notification_is_delivered_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let outbox_path = dir.path().join("outbox.toml");
+        let (server, up) = start_flaky_server();
+        let config = webhook_config(&server.url);
+
+        notify(&config, &data, &ip_changed());
+        let outbox = Outbox::load_from(&outbox_path).unwrap();
+        assert_eq!(outbox.通知.len(), 1);
+        let notification = &outbox.通知[0];
+        assert_eq!(notification.渠道, config.通知渠道[0].key());
+        assert!(!notification.渠道.contains(&server.url));
+        assert_eq!(notification.尝试次数, 1);
+        assert_eq!(notification.事件, ip_changed());
+        assert_eq!(notification.主题, "网络通IP变化通知");
+
+        up.store(true, Ordering::SeqCst);
+        let later = time(&notification.下次尝试时间);
+        flush(&config, &data, later - Duration::seconds(1)).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        flush(&config, &data, later).unwrap();
+        assert_eq!(server.requests().len(), 2);
+        let json: serde_json::Value = serde_json::from_str(&server.requests()[1].body).unwrap();
+        assert_eq!(json["event"], "ip_changed");
+        assert_eq!(json["new_ipv4"], "2.2.2.2");
+        assert_eq!(json["title"], "网络通IP变化通知");
+        assert!(!outbox_path.exists());
+    }
+
+    #[test]
+    fn retries_with_backoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = Data::load_from(dir.path().join("data.alice.toml")).unwrap();
+        let outbox_path = dir.path().join("outbox.alice.toml");
+        let (server, _) = start_flaky_server();
+        let config = Config {
+            补发间隔: 60,
+            最大补发间隔: 200,
+            ..webhook_config(&server.url)
+        };
+        let now = time("2024-07-01 08:00:00");
+        let e = anyhow::anyhow!("连接失败");
+        let message = test_message();
+        enqueue(
+            &config,
+            &data,
+            &config.通知渠道[0],
+            &ip_changed(),
+            &message,
+            &e,
+            now,
+        )
+        .unwrap();
+
+        for expected in ["08:01:00", "08:03:00", "08:06:20", "08:09:40"] {
+            let outbox = Outbox::load_from(&outbox_path).unwrap();
+            let notification = &outbox.通知[0];
+            assert_eq!(
+                notification.下次尝试时间,
+                format!("2024-07-01 {}", expected)
+            );
+            flush(&config, &data, time(&notification.下次尝试时间)).unwrap();
+        }
+        let outbox = Outbox::load_from(&outbox_path).unwrap();
+        assert_eq!(outbox.通知[0].尝试次数, 5);
+        assert!(outbox.通知[0].错误.contains("400"));
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn drops_expired_notification() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let outbox_path = dir.path().join("outbox.toml");
+        let (server, _) = start_flaky_server();
+        let config = Config {
+            补发期限: 600,
+            ..webhook_config(&server.url)
+        };
+        let now = time("2024-07-01 08:00:00");
+        let e = anyhow::anyhow!("连接失败");
+        let message = test_message();
+        enqueue(
+            &config,
+            &data,
+            &config.通知渠道[0],
+            &ip_changed(),
+            &message,
+            &e,
+            now,
+        )
+        .unwrap();
+
+        flush(&config, &data, now + Duration::seconds(600)).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        assert!(outbox_path.exists());
+        flush(&config, &data, now + Duration::seconds(601)).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        assert!(!outbox_path.exists());
+    }
+
+    #[test]
+    fn drops_notification_for_removed_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = Data::load_from(dir.path().join("data.toml")).unwrap();
+        let config = webhook_config("http://127.0.0.1:1/");
+        let now = time("2024-07-01 08:00:00");
+        let e = anyhow::anyhow!("连接失败");
+        let message = test_message();
+        enqueue(
+            &config,
+            &data,
+            &config.通知渠道[0],
+            &ip_changed(),
+            &message,
+            &e,
+            now,
+        )
+        .unwrap();
+        assert!(dir.path().join("outbox.toml").exists());
+
+        let config = webhook_config("http://127.0.0.1:2/");
+        flush(&config, &data, now + Duration::days(1)).unwrap();
+        assert!(!dir.path().join("outbox.toml").exists());
+    }
+}
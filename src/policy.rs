@@ -0,0 +1,183 @@
+use std::fmt::Display;
+
+use anyhow::Context;
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+/// 出口策略：在指定星期的时间段内使用指定的出口和使用时限
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ExitPolicy {
+    /// 1为星期一，7为星期日，为空时表示每天
+    pub 星期: Vec<u32>,
+    /// 如"08:00"
+    pub 开始: String,
+    /// 如"18:00"，早于开始时间时表示跨越午夜，等于开始时间时表示全天
+    pub 结束: String,
+    pub 出口: u8,
+    pub 使用时限: u32,
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        Self {
+            星期: Vec::new(),
+            开始: "00:00".to_owned(),
+            结束: "00:00".to_owned(),
+            出口: 8,
+            使用时限: 0,
+        }
+    }
+}
+
+impl Display for ExitPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.星期.is_empty() {
+            write!(f, "每天")?;
+        } else {
+            let weekdays: Vec<String> = self.星期.iter().map(u32::to_string).collect();
+            write!(f, "星期{}", weekdays.join(","))?;
+        }
+        write!(
+            f,
+            " {}-{} 出口: {} 使用时限: {}",
+            self.开始, self.结束, self.出口, self.使用时限
+        )
+    }
+}
+
+fn parse_time(time: &str) -> anyhow::Result<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .with_context(|| format!("出口策略中的时间格式错误，应为\"08:00\"的形式: {}", time))
+}
+
+impl ExitPolicy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        parse_time(&self.开始)?;
+        parse_time(&self.结束)?;
+        if let Some(weekday) = self.星期.iter().find(|weekday| !(1..=7).contains(*weekday)) {
+            anyhow::bail!("出口策略中的星期应为1到7: {}", weekday);
+        }
+        if self.出口 > 8 {
+            anyhow::bail!("出口策略中的出口应为0到8: {}", self.出口);
+        }
+        Ok(())
+    }
+
+    fn on_weekday(&self, weekday: u32) -> bool {
+        self.星期.is_empty() || self.星期.contains(&weekday)
+    }
+
+    /// 跨越午夜的时间段中，午夜之后的部分按开始那天的星期计算
+    pub fn is_active(&self, now: NaiveDateTime) -> anyhow::Result<bool> {
+        let start = parse_time(&self.开始)?;
+        let end = parse_time(&self.结束)?;
+        let time = now.time();
+        let today = now.weekday().number_from_monday();
+        let yesterday = (now - Duration::days(1)).weekday().number_from_monday();
+        Ok(if start == end {
+            self.on_weekday(today)
+        } else if start < end {
+            start <= time && time < end && self.on_weekday(today)
+        } else if time >= start {
+            self.on_weekday(today)
+        } else {
+            time < end && self.on_weekday(yesterday)
+        })
+    }
+}
+
+/// 返回当前生效的第一条出口策略，没有生效的策略时返回None
+pub fn active_policy(
+    policies: &[ExitPolicy],
+    now: NaiveDateTime,
+) -> anyhow::Result<Option<&ExitPolicy>> {
+    for policy in policies {
+        if policy.is_active(now)? {
+            return Ok(Some(policy));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    /// 2024-07-15是星期一
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 7, day)
+            .unwrap()
+            .and_time(parse_time(time).unwrap())
+    }
+
+    fn policy(weekdays: &[u32], start: &str, end: &str, exit: u8) -> ExitPolicy {
+        ExitPolicy {
+            星期: weekdays.to_vec(),
+            开始: start.to_owned(),
+            结束: end.to_owned(),
+            出口: exit,
+            使用时限: 0,
+        }
+    }
+
+    #[test]
+    fn daytime_range() {
+        let workday = policy(&[1, 2, 3, 4, 5], "08:00", "18:00", 0);
+        assert!(workday.is_active(at(15, "08:00")).unwrap());
+        assert!(workday.is_active(at(19, "17:59")).unwrap());
+        assert!(!workday.is_active(at(15, "18:00")).unwrap());
+        assert!(!workday.is_active(at(15, "07:59")).unwrap());
+        assert!(!workday.is_active(at(20, "12:00")).unwrap());
+    }
+
+    #[test]
+    fn overnight_range_uses_start_weekday() {
+        let friday_night = policy(&[5], "22:00", "06:00", 8);
+        assert!(friday_night.is_active(at(19, "23:00")).unwrap());
+        assert!(friday_night.is_active(at(20, "05:59")).unwrap());
+        assert!(!friday_night.is_active(at(20, "06:00")).unwrap());
+        assert!(!friday_night.is_active(at(19, "05:00")).unwrap());
+        assert!(!friday_night.is_active(at(20, "23:00")).unwrap());
+    }
+
+    #[test]
+    fn equal_start_and_end_means_all_day() {
+        let sunday = policy(&[7], "00:00", "00:00", 1);
+        assert!(sunday.is_active(at(21, "00:00")).unwrap());
+        assert!(sunday.is_active(at(21, "23:59")).unwrap());
+        assert!(!sunday.is_active(at(22, "00:00")).unwrap());
+    }
+
+    #[test]
+    fn first_active_policy_wins() {
+        let policies = [
+            policy(&[], "08:00", "18:00", 0),
+            policy(&[], "00:00", "00:00", 8),
+        ];
+        let active = |time| {
+            active_policy(&policies, at(15, time))
+                .unwrap()
+                .unwrap()
+                .出口
+        };
+        assert_eq!(active("09:00"), 0);
+        assert_eq!(active("19:00"), 8);
+        assert_eq!(
+            active_policy(&policies[..1], at(15, "19:00")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_policies_are_errors() {
+        let bad_time = policy(&[], "8点", "18:00", 0);
+        assert!(bad_time.validate().is_err());
+        assert!(bad_time.is_active(at(15, "09:00")).is_err());
+        assert!(policy(&[0], "08:00", "18:00", 0).validate().is_err());
+        assert!(policy(&[], "08:00", "18:00", 9).validate().is_err());
+        assert!(policy(&[1, 7], "22:00", "06:00", 8).validate().is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::is_request_timeout;
+
+/// 一次探测的结果，记录在data.toml中
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProbeResult {
+    pub 目标: String,
+    pub 出口: u8,
+    pub 成功: bool,
+    /// 毫秒
+    pub 延迟: u64,
+    pub 错误: String,
+    /// 因为超时而失败
+    pub 超时: bool,
+}
+
+/// `http://`、`https://`开头的目标发送HEAD请求，收到任何响应即为成功；
+/// `tcp://host:port`建立TCP连接即为成功
+fn probe(target: &str, timeout: Duration) -> anyhow::Result<()> {
+    if target.starts_with("http://") || target.starts_with("https://") {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .no_proxy()
+            .build()?;
+        client.head(target).send()?;
+        Ok(())
+    } else if let Some(addr) = target.strip_prefix("tcp://") {
+        let mut last_error = anyhow::anyhow!("无法解析地址: {}", addr);
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = e.into(),
+            }
+        }
+        Err(last_error)
+    } else {
+        anyhow::bail!(
+            "未知的探测目标，应以http://、https://或tcp://开头: {}",
+            target
+        )
+    }
+}
+
+/// 依次探测所有目标，exit为探测时使用的出口
+pub fn probe_all(targets: &[String], exit: u8, timeout: Duration) -> Vec<ProbeResult> {
+    targets
+        .iter()
+        .map(|target| {
+            let start = Instant::now();
+            let result = probe(target, timeout);
+            ProbeResult {
+                目标: target.clone(),
+                出口: exit,
+                成功: result.is_ok(),
+                延迟: start.elapsed().as_millis() as u64,
+                超时: result.as_ref().err().is_some_and(is_request_timeout),
+                错误: result.err().map(|e| format!("{:#}", e)).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::mock_gateway::{MockServer, Response};
+
+    const TIMEOUT: Duration = Duration::from_millis(300);
+
+    fn closed_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn tcp_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = format!("tcp://{}", listener.local_addr().unwrap());
+        let closed = format!("tcp://{}", closed_addr());
+
+        let results = probe_all(&[open.clone(), closed.clone()], 3, TIMEOUT);
+        assert_eq!(results[0].目标, open);
+        assert_eq!(results[0].出口, 3);
+        assert!(results[0].成功);
+        assert!(results[0].错误.is_empty());
+        assert!(!results[1].成功);
+        assert!(!results[1].错误.is_empty());
+    }
+
+    #[test]
+    fn http_probe() {
+        let server = MockServer::start("/", |_| Response::ok("").status(404));
+        let slow = MockServer::start("/", |_| Response::ok("").delay(Duration::from_secs(2)));
+        let closed = format!("http://{}/", closed_addr());
+
+        let results = probe_all(&[server.url.clone(), slow.url.clone(), closed], 0, TIMEOUT);
+        assert!(results[0].成功);
+        assert_eq!(server.requests()[0].method, "HEAD");
+        assert!(!results[1].成功);
+        assert!(results[1].延迟 >= TIMEOUT.as_millis() as u64);
+        assert!(results[1].超时);
+        assert!(!results[2].成功);
+        assert!(!results[2].超时);
+    }
+
+    #[test]
+    fn unknown_target_fails() {
+        let results = probe_all(&["ftp://example.com".to_owned()], 0, TIMEOUT);
+        assert!(!results[0].成功);
+        assert!(results[0].错误.starts_with("未知的探测目标"));
+    }
+}
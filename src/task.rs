@@ -1,81 +1,272 @@
+mod cron;
+mod systemd;
+mod windows;
+
 use std::{
+    fmt::Display,
+    io::Write,
     path::PathBuf,
-    process::{Command, Output},
+    process::{Command, Stdio},
 };
 
+use anyhow::Context;
 
+pub use cron::CronScheduler;
+pub use systemd::SystemdScheduler;
+pub use windows::WindowsScheduler;
 
 const TASK_NAME: &str = "wlt_task";
-const VBS_NAME: &str = "wlt_task.vbs";
 
-fn output_string(mut output: Output) -> anyhow::Result<String> {
-    let mut buf = output.stdout;
-    buf.append(&mut output.stderr);
-    Ok(String::from_utf8(buf)?)
+pub struct CommandOutput {
+    pub success: bool,
+    /// stdout和stderr拼接后的文本
+    pub text: String,
+}
+
+/// 执行外部命令，计划任务后端通过它调用powershell、systemctl、crontab等程序，测试时可以替换
+pub trait CommandRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        stdin: Option<&str>,
+    ) -> anyhow::Result<CommandOutput>;
 }
 
-fn make_task_vbs_file() -> anyhow::Result<()> {
-    let current_exe = std::env::current_exe()?;
-    let contents = format!(
-        r#"Set wShell = CreateObject("WScript.Shell")
-wShell.Run "cmd /c {} run", 0
-"#,
-        current_exe.to_string_lossy()
-    );
-    std::fs::write(VBS_NAME, contents)?;
-
-    Ok(())
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        stdin: Option<&str>,
+    ) -> anyhow::Result<CommandOutput> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("无法执行{}", program))?;
+        if let Some(stdin) = stdin {
+            child
+                .stdin
+                .take()
+                .expect("stdin is piped")
+                .write_all(stdin.as_bytes())?;
+        }
+        let mut output = child.wait_with_output()?;
+        let mut buf = output.stdout;
+        buf.append(&mut output.stderr);
+        Ok(CommandOutput {
+            success: output.status.success(),
+            text: String::from_utf8_lossy(&buf).to_string(),
+        })
+    }
 }
 
-pub fn set_task() -> anyhow::Result<String> {
-    make_task_vbs_file()?;
-    let wscript_path = PathBuf::new()
-        .join(std::env::var("WINDIR")?)
-        .join("System32")
-        .join("wscript.exe")
-        .to_string_lossy()
-        .to_string();
-    let current_dir = std::env::current_dir()?.to_string_lossy().to_string();
-    let vbs_path = std::env::current_dir()?
-        .join(VBS_NAME)
-        .to_string_lossy()
-        .to_string();
-    let command = format!(
-        r#"$action = New-ScheduledTaskAction -Execute {wscript_path} -WorkingDirectory {current_dir} -Argument {vbs_path}
-$description = "Configure WLT and send notification emails when the IP changes"
-$settings = New-ScheduledTaskSettingsSet -AllowStartIfOnBatteries -StartWhenAvailable -DontStopIfGoingOnBatteries -RunOnlyIfNetworkAvailable
-
-$triggers = @()
-$triggers += New-ScheduledTaskTrigger -Once -At "2000-01-01 00:00:00" -RepetitionInterval (New-TimeSpan -Minutes 5)
-
-$CIMTriggerClass = Get-CimClass -ClassName MSFT_TaskEventTrigger -Namespace Root/Microsoft/Windows/TaskScheduler:MSFT_TaskEventTrigger
-$trigger = New-CimInstance -CimClass $CIMTriggerClass -ClientOnly
-$trigger.Subscription = '<QueryList><Query Id="0" Path="Microsoft-Windows-NetworkProfile/Operational"><Select Path="Microsoft-Windows-NetworkProfile/Operational">*[System[(EventID=10000)]]</Select></Query></QueryList>'
-$trigger.Delay = "PT5S"
-$trigger.Enabled = $True
-$triggers += $trigger
-
-Register-ScheduledTask -Force -TaskName {TASK_NAME} -Action $action -Description $description -Settings $settings -Trigger $triggers"#
-    );
-
-    let output = Command::new("powershell").arg(command).output()?;
-    output_string(output)
+/// 运行命令，失败时返回包含命令输出的错误
+fn run_checked(
+    runner: &dyn CommandRunner,
+    program: &str,
+    args: &[&str],
+    stdin: Option<&str>,
+) -> anyhow::Result<String> {
+    let output = runner.run(program, args, stdin)?;
+    if output.success {
+        Ok(output.text)
+    } else {
+        anyhow::bail!("{} {} 执行失败\n{}", program, args.join(" "), output.text)
+    }
 }
 
-pub fn unset_task() -> anyhow::Result<String> {
-    let output = Command::new("powershell")
-        .arg(format!(
-            "Unregister-ScheduledTask -TaskName {TASK_NAME} -TaskPath \\ -Confirm:$false"
-        ))
-        .output()?;
-    output_string(output)
+/// 计划任务要执行的内容：在`working_dir`中执行`exe args`
+pub struct TaskSpec {
+    pub name: String,
+    pub exe: PathBuf,
+    pub working_dir: PathBuf,
+    /// 如`["run", "--profile", "alice"]`，其中的参数不需要转义
+    pub args: Vec<String>,
 }
 
-pub fn query_task() -> anyhow::Result<String> {
-    let output = Command::new("powershell")
-        .arg(format!(
-            "Get-ScheduledTaskInfo -TaskName {TASK_NAME} -TaskPath \\ -Verbose"
-        ))
-        .output()?;
-    output_string(output)
+impl TaskSpec {
+    /// profile不为None时，任务名加上profile的名字，执行`exe run --profile <profile>`
+    pub fn current(profile: Option<&str>) -> anyhow::Result<Self> {
+        let (name, args) = match profile {
+            Some(profile) => (
+                format!("{}_{}", TASK_NAME, profile),
+                vec!["run".to_owned(), "--profile".to_owned(), profile.to_owned()],
+            ),
+            None => (TASK_NAME.to_owned(), vec!["run".to_owned()]),
+        };
+        Ok(Self {
+            name,
+            exe: std::env::current_exe()?,
+            working_dir: std::env::current_dir()?,
+            args,
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TaskStatus {
+    pub installed: bool,
+    pub schedule: Vec<String>,
+    pub last_run: Option<String>,
+    pub next_run: Option<String>,
+    pub last_result: Option<String>,
+}
+
+impl Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.installed {
+            return write!(f, "计划任务未设置");
+        }
+        let or_none = |s: &Option<String>| s.clone().unwrap_or_else(|| "无".to_owned());
+        writeln!(f, "计划任务已设置")?;
+        for schedule in self.schedule.iter() {
+            writeln!(f, "时间表: {}", schedule)?;
+        }
+        writeln!(f, "上次运行: {}", or_none(&self.last_run))?;
+        writeln!(f, "下次运行: {}", or_none(&self.next_run))?;
+        write!(f, "上次运行结果: {}", or_none(&self.last_result))
+    }
+}
+
+pub trait Scheduler {
+    fn name(&self) -> &'static str;
+    /// 设置计划任务，重复设置会覆盖之前的设置，返回执行过程的输出
+    fn install(&self, task: &TaskSpec) -> anyhow::Result<String>;
+    /// 取消计划任务，返回执行过程的输出
+    fn uninstall(&self, task: &TaskSpec) -> anyhow::Result<String>;
+    fn status(&self, task: &TaskSpec) -> anyhow::Result<TaskStatus>;
+}
+
+pub const SCHEDULER_NAMES: [&str; 3] = ["windows", "systemd", "cron"];
+
+/// 根据名字选择计划任务后端，名字为None时根据当前系统自动选择：
+/// Windows使用计划任务；Linux在systemd启动的系统上使用systemd定时器，否则回退到crontab
+pub fn scheduler(name: Option<&str>) -> anyhow::Result<Box<dyn Scheduler>> {
+    let name = match name {
+        Some(name) => name,
+        None if cfg!(windows) => "windows",
+        None if std::path::Path::new("/run/systemd/system").is_dir() => "systemd",
+        None => "cron",
+    };
+    match name {
+        "windows" => Ok(Box::new(WindowsScheduler::new(SystemRunner))),
+        "systemd" => Ok(Box::new(SystemdScheduler::new(SystemRunner)?)),
+        "cron" => Ok(Box::new(CronScheduler::new(SystemRunner))),
+        _ => anyhow::bail!(
+            "未知的计划任务后端: {}，可选: {}",
+            name,
+            SCHEDULER_NAMES.join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    use super::*;
+
+    /// 执行过的命令（程序和参数用空格连接）及传入的stdin
+    type Call = (String, Option<String>);
+
+    /// 记录每次执行的命令，并按顺序返回预设的输出，预设的输出用完后返回成功和空输出
+    #[derive(Clone, Default)]
+    pub struct FakeRunner {
+        calls: Rc<RefCell<Vec<Call>>>,
+        outputs: Rc<RefCell<VecDeque<CommandOutput>>>,
+    }
+
+    impl FakeRunner {
+        pub fn with_outputs<'a>(outputs: impl IntoIterator<Item = (bool, &'a str)>) -> Self {
+            let runner = Self::default();
+            runner
+                .outputs
+                .borrow_mut()
+                .extend(outputs.into_iter().map(|(success, text)| CommandOutput {
+                    success,
+                    text: text.to_owned(),
+                }));
+            runner
+        }
+
+        /// 执行过的命令，程序和参数用空格连接
+        pub fn commands(&self) -> Vec<String> {
+            self.calls.borrow().iter().map(|(c, _)| c.clone()).collect()
+        }
+
+        /// 每次执行时传入的stdin
+        pub fn stdins(&self) -> Vec<Option<String>> {
+            self.calls.borrow().iter().map(|(_, s)| s.clone()).collect()
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(
+            &self,
+            program: &str,
+            args: &[&str],
+            stdin: Option<&str>,
+        ) -> anyhow::Result<CommandOutput> {
+            let command = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls
+                .borrow_mut()
+                .push((command, stdin.map(str::to_owned)));
+            Ok(self
+                .outputs
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or(CommandOutput {
+                    success: true,
+                    text: String::new(),
+                }))
+        }
+    }
+
+    pub fn test_task(working_dir: &std::path::Path) -> TaskSpec {
+        TaskSpec {
+            name: "wlt_task_alice".to_owned(),
+            exe: working_dir.join("wlt_task"),
+            working_dir: working_dir.to_owned(),
+            args: vec!["run".to_owned(), "--profile".to_owned(), "alice".to_owned()],
+        }
+    }
+
+    #[test]
+    fn run_checked_reports_output() {
+        let runner = FakeRunner::with_outputs([(false, "Unit not found.")]);
+        let e = run_checked(&runner, "systemctl", &["--user", "daemon-reload"], None).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "systemctl --user daemon-reload 执行失败\nUnit not found."
+        );
+    }
+
+    #[test]
+    fn status_display() {
+        assert_eq!(TaskStatus::default().to_string(), "计划任务未设置");
+        let status = TaskStatus {
+            installed: true,
+            schedule: vec!["每5分钟".to_owned()],
+            last_run: Some("2024-07-01 08:00:00".to_owned()),
+            next_run: None,
+            last_result: Some("0".to_owned()),
+        };
+        assert_eq!(
+            status.to_string(),
+            "计划任务已设置\n时间表: 每5分钟\n上次运行: 2024-07-01 08:00:00\n下次运行: 无\n上次运行结果: 0"
+        );
+    }
 }
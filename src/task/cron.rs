@@ -0,0 +1,286 @@
+use super::{run_checked, CommandRunner, Scheduler, TaskSpec, TaskStatus};
+
+fn begin_marker(task_name: &str) -> String {
+    format!("# BEGIN {task_name}")
+}
+
+fn end_marker(task_name: &str) -> String {
+    format!("# END {task_name}")
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// cron会把命令中的%当作换行，需要转义
+fn escape_percent(s: &str) -> String {
+    s.replace('%', r"\%")
+}
+
+/// 生成插入crontab的内容块，每5分钟（以及开机的时候）执行一次`wlt_task run`（及task.args中的其余参数）
+pub fn make_cron_block(task: &TaskSpec) -> String {
+    let args: Vec<String> = task.args.iter().map(|arg| shell_quote(arg)).collect();
+    let command = escape_percent(&format!(
+        "cd {} && {} {} >/dev/null 2>&1",
+        shell_quote(&task.working_dir.to_string_lossy()),
+        shell_quote(&task.exe.to_string_lossy()),
+        args.join(" ")
+    ));
+    format!(
+        "{}\n*/5 * * * * {command}\n@reboot sleep 60 && {command}\n{}\n",
+        begin_marker(&task.name),
+        end_marker(&task.name)
+    )
+}
+
+/// 删除crontab中由wlt_task生成的内容块，其余内容保持不变；内容块没有结束标记时报错，以免删除之后的内容
+pub fn remove_cron_block(crontab: &str, task_name: &str) -> anyhow::Result<String> {
+    let begin_marker = begin_marker(task_name);
+    let end_marker = end_marker(task_name);
+    let mut in_block = false;
+    let mut result = String::new();
+    for line in crontab.lines() {
+        if line.trim() == begin_marker {
+            in_block = true;
+        } else if in_block && line.trim() == end_marker {
+            in_block = false;
+        } else if !in_block {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    if in_block {
+        anyhow::bail!(
+            "crontab中{}没有对应的“{}”，请手动修改crontab",
+            begin_marker,
+            end_marker
+        );
+    }
+    Ok(result)
+}
+
+/// 插入内容块，若已存在则先删除旧的内容块，多次执行结果相同
+pub fn insert_cron_block(crontab: &str, task: &TaskSpec) -> anyhow::Result<String> {
+    let mut result = remove_cron_block(crontab, &task.name)?;
+    result.push_str(&make_cron_block(task));
+    Ok(result)
+}
+
+/// 返回内容块中的每一行任务，格式为(时间表, 命令)
+pub fn parse_cron_block(crontab: &str, task_name: &str) -> Option<Vec<(String, String)>> {
+    let begin_marker = begin_marker(task_name);
+    let end_marker = end_marker(task_name);
+    let mut lines = crontab
+        .lines()
+        .skip_while(|line| line.trim() != begin_marker);
+    lines.next()?;
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line == end_marker {
+            return Some(entries);
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let field_count = if line.starts_with('@') { 1 } else { 5 };
+        let fields: Vec<&str> = line.splitn(field_count + 1, ' ').collect();
+        if fields.len() == field_count + 1 {
+            entries.push((
+                fields[..field_count].join(" "),
+                fields[field_count].to_owned(),
+            ));
+        }
+    }
+    None
+}
+
+/// 通过`crontab`命令管理当前用户的crontab
+pub struct CronScheduler<R: CommandRunner> {
+    runner: R,
+}
+
+impl<R: CommandRunner> CronScheduler<R> {
+    pub fn new(runner: R) -> Self {
+        Self { runner }
+    }
+
+    fn read_crontab(&self) -> anyhow::Result<String> {
+        let output = self.runner.run("crontab", &["-l"], None)?;
+        if output.success {
+            Ok(output.text)
+        } else if output.text.contains("no crontab") {
+            Ok(String::new())
+        } else {
+            anyhow::bail!("crontab -l 执行失败\n{}", output.text)
+        }
+    }
+
+    fn write_crontab(&self, crontab: &str) -> anyhow::Result<String> {
+        run_checked(&self.runner, "crontab", &["-"], Some(crontab))
+    }
+}
+
+impl<R: CommandRunner> Scheduler for CronScheduler<R> {
+    fn name(&self) -> &'static str {
+        "cron"
+    }
+
+    fn install(&self, task: &TaskSpec) -> anyhow::Result<String> {
+        let crontab = insert_cron_block(&self.read_crontab()?, task)?;
+        let mut output = self.write_crontab(&crontab)?;
+        output.push_str(&format!("已写入crontab:\n{}", make_cron_block(task)));
+        Ok(output)
+    }
+
+    fn uninstall(&self, task: &TaskSpec) -> anyhow::Result<String> {
+        let crontab = self.read_crontab()?;
+        let new_crontab = remove_cron_block(&crontab, &task.name)?;
+        if new_crontab == crontab {
+            anyhow::bail!("crontab中没有{}的计划任务", task.name);
+        }
+        let mut output = self.write_crontab(&new_crontab)?;
+        output.push_str("已从crontab中删除计划任务");
+        Ok(output)
+    }
+
+    fn status(&self, task: &TaskSpec) -> anyhow::Result<TaskStatus> {
+        match parse_cron_block(&self.read_crontab()?, &task.name) {
+            Some(entries) => Ok(TaskStatus {
+                installed: true,
+                schedule: entries
+                    .into_iter()
+                    .map(|(schedule, command)| format!("{schedule} {command}"))
+                    .collect(),
+                ..Default::default()
+            }),
+            None => Ok(TaskStatus::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::task::tests::FakeRunner;
+
+    fn test_task() -> TaskSpec {
+        TaskSpec {
+            name: "wlt_task".to_owned(),
+            exe: PathBuf::from("/opt/wlt/wlt_task"),
+            working_dir: PathBuf::from("/home/alice/100%"),
+            args: vec!["run".to_owned()],
+        }
+    }
+
+    const USER_CRONTAB: &str = "MAILTO=alice\n0 3 * * * /usr/bin/backup\n";
+
+    #[test]
+    fn insert_twice_keeps_one_block() {
+        let task = test_task();
+        let once = insert_cron_block(USER_CRONTAB, &task).unwrap();
+        let twice = insert_cron_block(&once, &task).unwrap();
+        assert_eq!(once, twice);
+        assert_eq!(twice.matches("# BEGIN wlt_task").count(), 1);
+        assert!(twice.starts_with(USER_CRONTAB));
+    }
+
+    #[test]
+    fn command_escapes_percent() {
+        let block = make_cron_block(&test_task());
+        assert!(block.contains(
+            "*/5 * * * * cd '/home/alice/100\\%' && '/opt/wlt/wlt_task' 'run' >/dev/null 2>&1\n"
+        ));
+    }
+
+    #[test]
+    fn remove_keeps_other_entries() {
+        let crontab = format!(
+            "{}# BEGIN wlt_task_bob\n@reboot bob\n# END wlt_task_bob\n{}*/10 * * * * /usr/bin/sync\n",
+            USER_CRONTAB,
+            make_cron_block(&test_task())
+        );
+        assert_eq!(
+            remove_cron_block(&crontab, "wlt_task").unwrap(),
+            format!(
+                "{}# BEGIN wlt_task_bob\n@reboot bob\n# END wlt_task_bob\n*/10 * * * * /usr/bin/sync\n",
+                USER_CRONTAB
+            )
+        );
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        let crontab = format!("# BEGIN wlt_task\n*/5 * * * * old\n{}", USER_CRONTAB);
+        assert!(remove_cron_block(&crontab, "wlt_task").is_err());
+        assert!(insert_cron_block(&crontab, &test_task()).is_err());
+        assert_eq!(parse_cron_block(&crontab, "wlt_task"), None);
+    }
+
+    #[test]
+    fn parse_reads_block_back() {
+        let crontab = insert_cron_block(USER_CRONTAB, &test_task()).unwrap();
+        let command = "cd '/home/alice/100\\%' && '/opt/wlt/wlt_task' 'run' >/dev/null 2>&1";
+        assert_eq!(
+            parse_cron_block(&crontab, "wlt_task"),
+            Some(vec![
+                ("*/5 * * * *".to_owned(), command.to_owned()),
+                ("@reboot".to_owned(), format!("sleep 60 && {command}")),
+            ])
+        );
+        assert_eq!(parse_cron_block(USER_CRONTAB, "wlt_task"), None);
+    }
+
+    #[test]
+    fn install_and_uninstall_commands() {
+        let task = test_task();
+        let runner = FakeRunner::with_outputs([(true, USER_CRONTAB)]);
+        let scheduler = CronScheduler::new(runner.clone());
+        scheduler.install(&task).unwrap();
+        let installed = insert_cron_block(USER_CRONTAB, &task).unwrap();
+        assert_eq!(runner.commands(), ["crontab -l", "crontab -"]);
+        assert_eq!(runner.stdins(), [None, Some(installed.clone())]);
+
+        let runner = FakeRunner::with_outputs([(true, installed.as_str())]);
+        let scheduler = CronScheduler::new(runner.clone());
+        scheduler.uninstall(&task).unwrap();
+        assert_eq!(runner.commands(), ["crontab -l", "crontab -"]);
+        assert_eq!(runner.stdins()[1].as_deref(), Some(USER_CRONTAB));
+    }
+
+    #[test]
+    fn install_without_crontab() {
+        let task = test_task();
+        let runner = FakeRunner::with_outputs([(false, "no crontab for alice\n")]);
+        CronScheduler::new(runner.clone()).install(&task).unwrap();
+        assert_eq!(runner.stdins()[1], Some(make_cron_block(&task)));
+    }
+
+    #[test]
+    fn uninstall_without_block_does_not_write() {
+        let runner = FakeRunner::with_outputs([(true, USER_CRONTAB)]);
+        assert!(CronScheduler::new(runner.clone())
+            .uninstall(&test_task())
+            .is_err());
+        assert_eq!(runner.commands(), ["crontab -l"]);
+    }
+
+    #[test]
+    fn status_commands() {
+        let task = test_task();
+        let installed = insert_cron_block(USER_CRONTAB, &task).unwrap();
+        let runner = FakeRunner::with_outputs([(true, installed.as_str())]);
+        let status = CronScheduler::new(runner.clone()).status(&task).unwrap();
+        assert_eq!(runner.commands(), ["crontab -l"]);
+        assert!(status.installed);
+        assert_eq!(status.schedule.len(), 2);
+        assert!(status.schedule[1].starts_with("@reboot sleep 60 && cd "));
+
+        let runner = FakeRunner::with_outputs([(false, "no crontab for alice\n")]);
+        let status = CronScheduler::new(runner.clone()).status(&task).unwrap();
+        assert_eq!(status, TaskStatus::default());
+        assert_eq!(runner.commands(), ["crontab -l"]);
+    }
+}
@@ -0,0 +1,525 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use super::{run_checked, CommandOutput, CommandRunner, Scheduler, TaskSpec, TaskStatus};
+
+/// NetworkManager及networkd-dispatcher在网络连接时执行的脚本所在的目录
+const DISPATCHER_DIRS: [&str; 2] = [
+    "/etc/NetworkManager/dispatcher.d",
+    "/etc/networkd-dispatcher/routable.d",
+];
+
+pub struct SystemdUnits {
+    pub service: String,
+    pub timer: String,
+    /// 网络连接时由NetworkManager或networkd-dispatcher以root权限执行，启动用户服务
+    pub dispatcher: String,
+}
+
+/// systemd会把%开头的内容当作说明符替换
+fn escape_specifiers(s: &str) -> String {
+    s.replace('%', "%%")
+}
+
+/// ExecStart中的一个参数：用双引号包围，转义其中的`\`、`"`及说明符
+fn quote_arg(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        escape_specifiers(&s.replace('\\', "\\\\").replace('"', "\\\""))
+    )
+}
+
+/// 生成systemd用户服务及定时器的内容，每5分钟（以及用户的服务管理器启动的时候）执行一次`wlt_task run`（及task.args中的其余参数）；
+/// 用户的服务管理器中没有network-online.target，网络连接时由dispatcher脚本通过`systemctl --user --machine=<user>@`启动服务
+pub fn make_unit_files(task: &TaskSpec, user: &str) -> SystemdUnits {
+    let command = std::iter::once(task.exe.to_string_lossy().into_owned())
+        .chain(task.args.iter().cloned())
+        .map(|arg| quote_arg(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let working_dir = escape_specifiers(&task.working_dir.to_string_lossy());
+    let task_name = &task.name;
+    let service = format!(
+        r#"[Unit]
+Description=Configure WLT and send notification emails when the IP changes
+
+[Service]
+Type=oneshot
+WorkingDirectory={working_dir}
+ExecStart={command}
+
+[Install]
+WantedBy=default.target
+"#
+    );
+    let timer = format!(
+        r#"[Unit]
+Description=Run {task_name} every 5 minutes
+
+[Timer]
+OnBootSec=1min
+OnUnitActiveSec=5min
+Persistent=true
+Unit={task_name}.service
+
+[Install]
+WantedBy=timers.target
+"#
+    );
+    let dispatcher = format!(
+        r#"#!/bin/sh
+# 由wlt_task set生成，网络连接时启动systemd用户服务{task_name}.service
+# NetworkManager的第2个参数为事件，networkd-dispatcher执行routable.d中的脚本时没有参数
+case "$2" in
+    up|connectivity-change|"") ;;
+    *) exit 0 ;;
+esac
+exec systemctl --user --machine={user}@ --no-block start {task_name}.service
+"#
+    );
+    SystemdUnits {
+        service,
+        timer,
+        dispatcher,
+    }
+}
+
+fn default_unit_dir() -> anyhow::Result<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            PathBuf::from(std::env::var_os("HOME").context("没有设置HOME环境变量")?).join(".config")
+        }
+    };
+    Ok(config_dir.join("systemd").join("user"))
+}
+
+fn service_name(task: &TaskSpec) -> String {
+    format!("{}.service", task.name)
+}
+
+fn timer_name(task: &TaskSpec) -> String {
+    format!("{}.timer", task.name)
+}
+
+/// dispatcher脚本先写入单元文件所在的目录，再以root权限复制到各个dispatcher目录
+fn dispatcher_name(task: &TaskSpec) -> String {
+    format!("{}-dispatcher.sh", task.name)
+}
+
+fn dispatcher_hook_name(task: &TaskSpec) -> String {
+    format!("50-{}", task.name)
+}
+
+/// 解析`systemctl show`输出的`key=value`
+fn show_property(show_output: &str, key: &str) -> Option<String> {
+    show_output
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .filter(|value| !value.is_empty() && *value != "n/a")
+        .map(str::to_owned)
+}
+
+/// 通过`systemctl --user`管理的systemd用户服务及定时器，以及网络连接时启动服务的dispatcher脚本
+pub struct SystemdScheduler<R: CommandRunner> {
+    runner: R,
+    unit_dir: PathBuf,
+    /// 运行服务的用户，dispatcher脚本以root权限执行，需要指定用户的服务管理器
+    user: String,
+    /// 只在其中存在的目录中安装dispatcher脚本
+    dispatcher_dirs: Vec<PathBuf>,
+}
+
+impl<R: CommandRunner> SystemdScheduler<R> {
+    pub fn new(runner: R) -> anyhow::Result<Self> {
+        let user = std::env::var("USER").context("没有设置USER环境变量")?;
+        Ok(Self::with_unit_dir(runner, default_unit_dir()?)
+            .with_dispatcher_dirs(user, DISPATCHER_DIRS.iter().map(PathBuf::from).collect()))
+    }
+
+    /// 不安装dispatcher脚本
+    pub fn with_unit_dir(runner: R, unit_dir: PathBuf) -> Self {
+        Self {
+            runner,
+            unit_dir,
+            user: String::new(),
+            dispatcher_dirs: Vec::new(),
+        }
+    }
+
+    pub fn with_dispatcher_dirs(mut self, user: String, dispatcher_dirs: Vec<PathBuf>) -> Self {
+        self.user = user;
+        self.dispatcher_dirs = dispatcher_dirs;
+        self
+    }
+
+    /// 存在的dispatcher目录中脚本的路径
+    fn dispatcher_hooks(&self, task: &TaskSpec) -> Vec<PathBuf> {
+        self.dispatcher_dirs
+            .iter()
+            .filter(|dir| dir.is_dir())
+            .map(|dir| dir.join(dispatcher_hook_name(task)))
+            .collect()
+    }
+
+    fn systemctl(&self, args: &[&str]) -> anyhow::Result<String> {
+        let args: Vec<&str> = std::iter::once("--user")
+            .chain(args.iter().copied())
+            .collect();
+        run_checked(&self.runner, "systemctl", &args, None)
+    }
+
+    /// 不检查是否成功，用于停用可能没有加载的单元
+    fn systemctl_unchecked(&self, args: &[&str]) -> anyhow::Result<CommandOutput> {
+        let args: Vec<&str> = std::iter::once("--user")
+            .chain(args.iter().copied())
+            .collect();
+        self.runner.run("systemctl", &args, None)
+    }
+
+    /// 把dispatcher脚本复制到存在的dispatcher目录中，写入/etc需要root权限，没有权限时提示手动复制而不是报错
+    fn install_dispatcher(&self, task: &TaskSpec, dispatcher: &str) -> anyhow::Result<String> {
+        let script_path = self.unit_dir.join(dispatcher_name(task));
+        std::fs::write(&script_path, dispatcher)?;
+        let script_path = script_path.to_string_lossy();
+        let mut output = format!("已写入{}\n", script_path);
+        let hooks = self.dispatcher_hooks(task);
+        if hooks.is_empty() {
+            output.push_str(
+                "没有找到NetworkManager或networkd-dispatcher，网络连接时不会执行，需要时可以同时运行`wlt_task watch`\n",
+            );
+        }
+        for hook in hooks {
+            let hook = hook.to_string_lossy();
+            let result = self
+                .runner
+                .run("install", &["-m", "755", &script_path, &hook], None)?;
+            output.push_str(&result.text);
+            if result.success {
+                output.push_str(&format!("已写入{}\n", hook));
+            } else {
+                output.push_str(&format!(
+                    "无法写入{}，网络连接时不会执行，请以root权限执行`install -m 755 {} {}`\n",
+                    hook, script_path, hook
+                ));
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl<R: CommandRunner> Scheduler for SystemdScheduler<R> {
+    fn name(&self) -> &'static str {
+        "systemd"
+    }
+
+    fn install(&self, task: &TaskSpec) -> anyhow::Result<String> {
+        let units = make_unit_files(task, &self.user);
+        std::fs::create_dir_all(&self.unit_dir)?;
+        let service_path = self.unit_dir.join(service_name(task));
+        let timer_path = self.unit_dir.join(timer_name(task));
+        std::fs::write(&service_path, units.service)?;
+        std::fs::write(&timer_path, units.timer)?;
+
+        let mut output = format!(
+            "已写入{}\n已写入{}\n",
+            service_path.to_string_lossy(),
+            timer_path.to_string_lossy()
+        );
+        output.push_str(&self.systemctl(&["daemon-reload"])?);
+        output.push_str(&self.systemctl(&["enable", &service_name(task)])?);
+        output.push_str(&self.systemctl(&["enable", "--now", &timer_name(task)])?);
+        if !self.dispatcher_dirs.is_empty() {
+            output.push_str(&self.install_dispatcher(task, &units.dispatcher)?);
+        }
+        Ok(output)
+    }
+
+    /// 定时器或服务没有加载时停用失败也继续删除单元文件，两者都不存在时报错
+    fn uninstall(&self, task: &TaskSpec) -> anyhow::Result<String> {
+        let mut output = String::new();
+        let mut found = false;
+        for args in [
+            ["disable", "--now", &timer_name(task)].as_slice(),
+            ["disable", &service_name(task)].as_slice(),
+        ] {
+            let result = self.systemctl_unchecked(args)?;
+            found |= result.success;
+            output.push_str(&result.text);
+        }
+        for unit_path in [
+            self.unit_dir.join(service_name(task)),
+            self.unit_dir.join(timer_name(task)),
+            self.unit_dir.join(dispatcher_name(task)),
+        ] {
+            if unit_path.exists() {
+                std::fs::remove_file(&unit_path)?;
+                found = true;
+                output.push_str(&format!("已删除{}\n", unit_path.to_string_lossy()));
+            }
+        }
+        for hook in self.dispatcher_hooks(task) {
+            if hook.exists() {
+                found = true;
+                let hook = hook.to_string_lossy();
+                let result = self.runner.run("rm", &["-f", &hook], None)?;
+                output.push_str(&result.text);
+                if result.success {
+                    output.push_str(&format!("已删除{}\n", hook));
+                } else {
+                    output.push_str(&format!(
+                        "无法删除{}，请以root权限执行`rm -f {}`\n",
+                        hook, hook
+                    ));
+                }
+            }
+        }
+        if !found {
+            anyhow::bail!("没有{}的systemd定时器\n{}", task.name, output);
+        }
+        output.push_str(&self.systemctl(&["daemon-reload"])?);
+        Ok(output)
+    }
+
+    fn status(&self, task: &TaskSpec) -> anyhow::Result<TaskStatus> {
+        let timer = self.systemctl(&[
+            "show",
+            &timer_name(task),
+            "--property=LoadState,LastTriggerUSec,NextElapseUSecRealtime",
+        ])?;
+        if show_property(&timer, "LoadState").as_deref() != Some("loaded") {
+            return Ok(TaskStatus::default());
+        }
+        let service = self.systemctl(&[
+            "show",
+            &service_name(task),
+            "--property=Result,ExecMainStatus",
+        ])?;
+        let last_result = match (
+            show_property(&service, "ExecMainStatus"),
+            show_property(&service, "Result"),
+        ) {
+            (Some(status), Some(result)) => Some(format!("{} ({})", status, result)),
+            (status, result) => status.or(result),
+        };
+        let mut schedule = vec!["每5分钟，以及用户的服务管理器启动时".to_owned()];
+        schedule.extend(
+            self.dispatcher_hooks(task)
+                .into_iter()
+                .filter(|hook| hook.exists())
+                .map(|hook| format!("网络连接时（{}）", hook.to_string_lossy())),
+        );
+        Ok(TaskStatus {
+            installed: true,
+            schedule,
+            last_run: show_property(&timer, "LastTriggerUSec"),
+            next_run: show_property(&timer, "NextElapseUSecRealtime"),
+            last_result,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::tests::{test_task, FakeRunner};
+
+    #[test]
+    fn unit_files_escape_specifiers() {
+        let task = TaskSpec {
+            name: "wlt_task_alice".to_owned(),
+            exe: PathBuf::from("/home/a\"b/100%/wlt_task"),
+            working_dir: PathBuf::from("/home/a\"b/100%"),
+            args: vec![
+                "run".to_owned(),
+                "--profile".to_owned(),
+                "a\"l ice%".to_owned(),
+            ],
+        };
+        let units = make_unit_files(&task, "alice");
+        assert!(units
+            .service
+            .contains("WorkingDirectory=/home/a\"b/100%%\n"));
+        assert!(units.service.contains(
+            "ExecStart=\"/home/a\\\"b/100%%/wlt_task\" \"run\" \"--profile\" \"a\\\"l ice%%\"\n"
+        ));
+        assert!(units.service.contains("WantedBy=default.target"));
+        assert!(units.timer.contains("Unit=wlt_task_alice.service"));
+        assert!(units.timer.contains("OnUnitActiveSec=5min"));
+        assert!(units.dispatcher.starts_with("#!/bin/sh\n"));
+        assert!(units.dispatcher.contains(
+            "exec systemctl --user --machine=alice@ --no-block start wlt_task_alice.service\n"
+        ));
+    }
+
+    #[test]
+    fn install_and_uninstall_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit_dir = dir.path().join("systemd").join("user");
+        let task = test_task(dir.path());
+        let runner = FakeRunner::default();
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), unit_dir.clone());
+
+        scheduler.install(&task).unwrap();
+        assert_eq!(
+            runner.commands(),
+            [
+                "systemctl --user daemon-reload",
+                "systemctl --user enable wlt_task_alice.service",
+                "systemctl --user enable --now wlt_task_alice.timer",
+            ]
+        );
+        assert!(unit_dir.join("wlt_task_alice.service").exists());
+        assert!(unit_dir.join("wlt_task_alice.timer").exists());
+
+        scheduler.uninstall(&task).unwrap();
+        assert_eq!(
+            runner.commands()[3..],
+            [
+                "systemctl --user disable --now wlt_task_alice.timer",
+                "systemctl --user disable wlt_task_alice.service",
+                "systemctl --user daemon-reload",
+            ]
+        );
+        assert!(!unit_dir.join("wlt_task_alice.service").exists());
+        assert!(!unit_dir.join("wlt_task_alice.timer").exists());
+    }
+
+    #[test]
+    fn install_and_uninstall_dispatcher_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit_dir = dir.path().join("user");
+        let nm_dir = dir.path().join("dispatcher.d");
+        std::fs::create_dir(&nm_dir).unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::default();
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), unit_dir.clone())
+            .with_dispatcher_dirs(
+                "alice".to_owned(),
+                vec![nm_dir.clone(), dir.path().join("routable.d")],
+            );
+
+        let output = scheduler.install(&task).unwrap();
+        let script = unit_dir.join("wlt_task_alice-dispatcher.sh");
+        let hook = nm_dir.join("50-wlt_task_alice");
+        assert!(std::fs::read_to_string(&script)
+            .unwrap()
+            .contains("--machine=alice@"));
+        assert_eq!(
+            runner.commands()[3..],
+            [format!(
+                "install -m 755 {} {}",
+                script.to_string_lossy(),
+                hook.to_string_lossy()
+            )]
+        );
+        assert!(output.contains(&format!("已写入{}", hook.to_string_lossy())));
+
+        std::fs::write(&hook, "").unwrap();
+        let runner = FakeRunner::with_outputs([
+            (true, ""),
+            (true, ""),
+            (false, "rm: cannot remove: Permission denied\n"),
+        ]);
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), unit_dir.clone())
+            .with_dispatcher_dirs("alice".to_owned(), vec![nm_dir.clone()]);
+        let output = scheduler.uninstall(&task).unwrap();
+        assert_eq!(
+            runner.commands()[2],
+            format!("rm -f {}", hook.to_string_lossy())
+        );
+        assert!(output.contains("请以root权限执行`rm -f"));
+        assert!(!script.exists());
+    }
+
+    #[test]
+    fn install_without_permission_for_dispatcher_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::with_outputs([
+            (true, ""),
+            (true, ""),
+            (true, ""),
+            (
+                false,
+                "install: cannot create regular file: Permission denied\n",
+            ),
+        ]);
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), dir.path().join("user"))
+            .with_dispatcher_dirs("alice".to_owned(), vec![dir.path().to_owned()]);
+        let output = scheduler.install(&task).unwrap();
+        assert!(output.contains("网络连接时不会执行，请以root权限执行`install -m 755"));
+
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), dir.path().join("user"))
+            .with_dispatcher_dirs("alice".to_owned(), vec![dir.path().join("missing")]);
+        let output = scheduler.install(&task).unwrap();
+        assert!(output.contains("没有找到NetworkManager或networkd-dispatcher"));
+    }
+
+    #[test]
+    fn uninstall_tolerates_unloaded_timer() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        std::fs::write(dir.path().join("wlt_task_alice.timer"), "").unwrap();
+        let not_loaded = "Failed to disable unit: Unit file wlt_task_alice.timer does not exist.";
+        let runner = FakeRunner::with_outputs([(false, not_loaded), (false, not_loaded)]);
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), dir.path().to_owned());
+        scheduler.uninstall(&task).unwrap();
+        assert!(!dir.path().join("wlt_task_alice.timer").exists());
+        assert_eq!(
+            runner.commands().last().unwrap(),
+            "systemctl --user daemon-reload"
+        );
+
+        let runner = FakeRunner::with_outputs([(false, not_loaded), (false, not_loaded)]);
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), dir.path().to_owned());
+        assert!(scheduler.uninstall(&task).is_err());
+        assert_eq!(runner.commands().len(), 2);
+    }
+
+    #[test]
+    fn status_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::with_outputs([
+            (
+                true,
+                "LoadState=loaded\nLastTriggerUSec=Mon 2024-07-01 08:00:00 CST\nNextElapseUSecRealtime=Mon 2024-07-01 08:05:00 CST\n",
+            ),
+            (true, "Result=success\nExecMainStatus=0\n"),
+        ]);
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), dir.path().to_owned());
+        let status = scheduler.status(&task).unwrap();
+        assert_eq!(
+            runner.commands(),
+            [
+                "systemctl --user show wlt_task_alice.timer --property=LoadState,LastTriggerUSec,NextElapseUSecRealtime",
+                "systemctl --user show wlt_task_alice.service --property=Result,ExecMainStatus",
+            ]
+        );
+        assert!(status.installed);
+        assert_eq!(
+            status.last_run.as_deref(),
+            Some("Mon 2024-07-01 08:00:00 CST")
+        );
+        assert_eq!(
+            status.next_run.as_deref(),
+            Some("Mon 2024-07-01 08:05:00 CST")
+        );
+        assert_eq!(status.last_result.as_deref(), Some("0 (success)"));
+    }
+
+    #[test]
+    fn status_when_not_installed() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::with_outputs([(
+            true,
+            "LoadState=not-found\nLastTriggerUSec=n/a\nNextElapseUSecRealtime=\n",
+        )]);
+        let scheduler = SystemdScheduler::with_unit_dir(runner.clone(), dir.path().to_owned());
+        assert_eq!(scheduler.status(&task).unwrap(), TaskStatus::default());
+        assert_eq!(runner.commands().len(), 1);
+    }
+}
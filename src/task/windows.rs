@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use super::{run_checked, CommandRunner, Scheduler, TaskSpec, TaskStatus};
+
+fn make_task_vbs(task: &TaskSpec) -> String {
+    format!(
+        r#"Set wShell = CreateObject("WScript.Shell")
+wShell.Run "cmd /c {} {}", 0
+"#,
+        task.exe.to_string_lossy(),
+        task.args.join(" ")
+    )
+}
+
+fn make_register_command(task: &TaskSpec, wscript_path: &Path, vbs_path: &Path) -> String {
+    let wscript_path = wscript_path.to_string_lossy();
+    let current_dir = task.working_dir.to_string_lossy();
+    let vbs_path = vbs_path.to_string_lossy();
+    let task_name = &task.name;
+    format!(
+        r#"$action = New-ScheduledTaskAction -Execute {wscript_path} -WorkingDirectory {current_dir} -Argument {vbs_path}
+$description = "Configure WLT and send notification emails when the IP changes"
+$settings = New-ScheduledTaskSettingsSet -AllowStartIfOnBatteries -StartWhenAvailable -DontStopIfGoingOnBatteries -RunOnlyIfNetworkAvailable
+
+$triggers = @()
+$triggers += New-ScheduledTaskTrigger -Once -At "2000-01-01 00:00:00" -RepetitionInterval (New-TimeSpan -Minutes 5)
+
+$CIMTriggerClass = Get-CimClass -ClassName MSFT_TaskEventTrigger -Namespace Root/Microsoft/Windows/TaskScheduler:MSFT_TaskEventTrigger
+$trigger = New-CimInstance -CimClass $CIMTriggerClass -ClientOnly
+$trigger.Subscription = '<QueryList><Query Id="0" Path="Microsoft-Windows-NetworkProfile/Operational"><Select Path="Microsoft-Windows-NetworkProfile/Operational">*[System[(EventID=10000)]]</Select></Query></QueryList>'
+$trigger.Delay = "PT5S"
+$trigger.Enabled = $True
+$triggers += $trigger
+
+Register-ScheduledTask -Force -TaskName {task_name} -Action $action -Description $description -Settings $settings -Trigger $triggers"#
+    )
+}
+
+/// 解析`Format-List`输出的`key : value`
+fn list_property(list_output: &str, key: &str) -> Option<String> {
+    list_output
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+/// 通过powershell调用Windows计划任务
+pub struct WindowsScheduler<R: CommandRunner> {
+    runner: R,
+    /// Windows文件夹，wscript.exe在其中的System32中，没有设置WINDIR环境变量时为None，此时无法设置计划任务
+    windir: Option<PathBuf>,
+}
+
+impl<R: CommandRunner> WindowsScheduler<R> {
+    pub fn new(runner: R) -> Self {
+        Self::with_windir(runner, std::env::var_os("WINDIR").map(PathBuf::from))
+    }
+
+    pub fn with_windir(runner: R, windir: Option<PathBuf>) -> Self {
+        Self { runner, windir }
+    }
+
+    fn powershell(&self, command: &str) -> anyhow::Result<String> {
+        run_checked(&self.runner, "powershell", &[command], None)
+    }
+}
+
+impl<R: CommandRunner> Scheduler for WindowsScheduler<R> {
+    fn name(&self) -> &'static str {
+        "windows"
+    }
+
+    fn install(&self, task: &TaskSpec) -> anyhow::Result<String> {
+        let vbs_path = task.working_dir.join(format!("{}.vbs", task.name));
+        std::fs::write(&vbs_path, make_task_vbs(task))?;
+        let wscript_path = self
+            .windir
+            .as_ref()
+            .context("没有设置WINDIR环境变量")?
+            .join("System32")
+            .join("wscript.exe");
+        self.powershell(&make_register_command(task, &wscript_path, &vbs_path))
+    }
+
+    fn uninstall(&self, task: &TaskSpec) -> anyhow::Result<String> {
+        self.powershell(&format!(
+            "Unregister-ScheduledTask -TaskName {} -TaskPath \\ -Confirm:$false",
+            task.name
+        ))
+    }
+
+    fn status(&self, task: &TaskSpec) -> anyhow::Result<TaskStatus> {
+        let output = self.runner.run(
+            "powershell",
+            &[&format!(
+                "Get-ScheduledTaskInfo -TaskName {} -TaskPath \\ | Format-List LastRunTime,LastTaskResult,NextRunTime",
+                task.name
+            )],
+            None,
+        )?;
+        if !output.success {
+            return Ok(TaskStatus::default());
+        }
+        Ok(TaskStatus {
+            installed: true,
+            schedule: vec!["每5分钟，以及网络连接时".to_owned()],
+            last_run: list_property(&output.text, "LastRunTime"),
+            next_run: list_property(&output.text, "NextRunTime"),
+            last_result: list_property(&output.text, "LastTaskResult"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::tests::{test_task, FakeRunner};
+
+    #[test]
+    fn install_and_uninstall_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::default();
+        let scheduler =
+            WindowsScheduler::with_windir(runner.clone(), Some(dir.path().join("Windows")));
+
+        scheduler.install(&task).unwrap();
+        let vbs_path = dir.path().join("wlt_task_alice.vbs");
+        assert_eq!(
+            std::fs::read_to_string(&vbs_path).unwrap(),
+            make_task_vbs(&task)
+        );
+        let wscript_path = dir
+            .path()
+            .join("Windows")
+            .join("System32")
+            .join("wscript.exe");
+        assert_eq!(
+            runner.commands(),
+            [format!(
+                "powershell {}",
+                make_register_command(&task, &wscript_path, &vbs_path)
+            )]
+        );
+        assert!(runner.commands()[0]
+            .contains("Register-ScheduledTask -Force -TaskName wlt_task_alice "));
+
+        scheduler.uninstall(&task).unwrap();
+        assert_eq!(
+            runner.commands()[1],
+            "powershell Unregister-ScheduledTask -TaskName wlt_task_alice -TaskPath \\ -Confirm:$false"
+        );
+    }
+
+    #[test]
+    fn status_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::with_outputs([(
+            true,
+            "\r\nLastRunTime    : 2024/7/1 8:00:00\r\nLastTaskResult : 0\r\nNextRunTime    : 2024/7/1 8:05:00\r\n",
+        )]);
+        let status = WindowsScheduler::new(runner.clone()).status(&task).unwrap();
+        assert_eq!(
+            runner.commands(),
+            ["powershell Get-ScheduledTaskInfo -TaskName wlt_task_alice -TaskPath \\ | Format-List LastRunTime,LastTaskResult,NextRunTime"]
+        );
+        assert!(status.installed);
+        assert_eq!(status.last_run.as_deref(), Some("2024/7/1 8:00:00"));
+        assert_eq!(status.next_run.as_deref(), Some("2024/7/1 8:05:00"));
+        assert_eq!(status.last_result.as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn status_when_not_installed() {
+        let dir = tempfile::tempdir().unwrap();
+        let task = test_task(dir.path());
+        let runner = FakeRunner::with_outputs([(
+            false,
+            "Get-ScheduledTaskInfo : No MSFT_ScheduledTask objects found",
+        )]);
+        let status = WindowsScheduler::new(runner.clone()).status(&task).unwrap();
+        assert_eq!(status, TaskStatus::default());
+        assert_eq!(runner.commands().len(), 1);
+    }
+}
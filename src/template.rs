@@ -0,0 +1,370 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use anyhow::Context as _;
+use chrono::Local;
+use minijinja::{AutoEscape, Environment, UndefinedBehavior};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::data::{Data, IpChange, TIME_FORMAT};
+use crate::log::log;
+use crate::notify::{Event, EventKind, Message};
+use crate::policy::active_policy;
+use crate::utils::hostname;
+use crate::wlt::EXIT_NAMES;
+
+/// config.toml中"通知模板"的一项，为空的项使用默认模板
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MessageTemplate {
+    pub 事件: EventKind,
+    pub 主题: String,
+    pub 内容: String,
+    /// 不为空时邮件同时包含纯文本和HTML两种内容
+    pub HTML内容: String,
+}
+
+impl Default for MessageTemplate {
+    fn default() -> Self {
+        Self {
+            事件: EventKind::IpChanged,
+            主题: String::new(),
+            内容: String::new(),
+            HTML内容: String::new(),
+        }
+    }
+}
+
+impl MessageTemplate {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate("通知模板中的主题", &self.主题)?;
+        validate("通知模板中的内容", &self.内容)?;
+        validate("通知模板中的HTML内容", &self.HTML内容)
+    }
+}
+
+/// 模板中可以使用的变量
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Context {
+    事件: &'static str,
+    事件ID: &'static str,
+    主机名: String,
+    机器名: String,
+    网络通用户名: String,
+    时间: String,
+    旧IPv4: String,
+    新IPv4: String,
+    旧IPv6: String,
+    新IPv6: String,
+    出口: u8,
+    出口名: String,
+    旧出口: u8,
+    新出口: u8,
+    原因: String,
+    错误: String,
+    历史: Vec<IpChange>,
+}
+
+fn exit_name(exit: u8) -> String {
+    EXIT_NAMES.get(exit as usize).unwrap_or(&"").to_string()
+}
+
+impl Context {
+    pub fn new(config: &Config, data: &Data, event: &Event) -> Self {
+        let hostname = hostname();
+        // 探测选择的出口优先，其次是生效的出口策略
+        let exit = data.出口.unwrap_or_else(|| {
+            match active_policy(&config.出口策略, Local::now().naive_local()) {
+                Ok(Some(policy)) => policy.出口,
+                _ => config.网络通出口,
+            }
+        });
+        let mut context = Self {
+            事件: event.kind().name(),
+            事件ID: event.kind().id(),
+            机器名: match config.机器名.is_empty() {
+                true => hostname.clone(),
+                false => config.机器名.clone(),
+            },
+            主机名: hostname,
+            网络通用户名: config.网络通用户名.clone(),
+            时间: Local::now().format(TIME_FORMAT).to_string(),
+            旧IPv4: data.ipv4.clone(),
+            新IPv4: data.ipv4.clone(),
+            旧IPv6: data.ipv6.clone(),
+            新IPv6: data.ipv6.clone(),
+            出口: exit,
+            出口名: exit_name(exit),
+            旧出口: exit,
+            新出口: exit,
+            原因: String::new(),
+            错误: String::new(),
+            历史: data.IP历史.clone(),
+        };
+        match event {
+            Event::IpChanged {
+                old_ipv4,
+                new_ipv4,
+                old_ipv6,
+                new_ipv6,
+            } => {
+                context.旧IPv4 = old_ipv4.clone();
+                context.新IPv4 = new_ipv4.clone();
+                context.旧IPv6 = old_ipv6.clone();
+                context.新IPv6 = new_ipv6.clone();
+            }
+            Event::ExitChanged {
+                old_exit,
+                new_exit,
+                reason,
+            } => {
+                context.旧出口 = *old_exit;
+                context.新出口 = *new_exit;
+                context.出口 = *new_exit;
+                context.出口名 = exit_name(*new_exit);
+                context.原因 = reason.clone();
+            }
+            Event::Error { error } | Event::Recovered { error } | Event::LoginFailed { error } => {
+                context.错误 = error.clone();
+            }
+        }
+        context
+    }
+
+    /// 检查模板时使用，IP历史中有一项以便检查循环
+    fn sample() -> Self {
+        Self {
+            事件: EventKind::IpChanged.name(),
+            事件ID: EventKind::IpChanged.id(),
+            历史: vec![IpChange::default()],
+            ..Default::default()
+        }
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_keep_trailing_newline(true);
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_auto_escape_callback(|name| match name.ends_with("HTML内容") {
+        true => AutoEscape::Html,
+        false => AutoEscape::None,
+    });
+    env
+}
+
+/// 渲染模板，name以HTML内容结尾时转义变量中的HTML字符
+pub fn render(name: &str, template: &str, context: &Context) -> anyhow::Result<String> {
+    Ok(environment().render_named_str(name, template, context)?)
+}
+
+/// 检查模板的语法，以及是否使用了不存在的变量，如{{ 新IPV4 }}
+pub fn validate(name: &str, template: &str) -> anyhow::Result<()> {
+    let env = environment();
+    let compiled = env
+        .template_from_named_str(name, template)
+        .with_context(|| format!("{}模板有误", name))?;
+    let mut known: HashSet<String> = env.globals().map(|(name, _)| name.to_owned()).collect();
+    if let serde_json::Value::Object(variables) = serde_json::to_value(Context::sample())? {
+        known.extend(variables.into_iter().map(|(name, _)| name));
+    }
+    let mut unknown: Vec<String> = compiled
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|variable| !known.contains(variable))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        anyhow::bail!("{}中有未知的变量: {}", name, unknown.join(", "));
+    }
+    compiled
+        .render(Context::sample())
+        .with_context(|| format!("{}模板有误", name))?;
+    Ok(())
+}
+
+/// 把以前的{新IPv4}形式的占位符改为{{ 新IPv4 }}，已经使用新语法的模板不变
+pub fn migrate(template: &str) -> Cow<'_, str> {
+    if ["{{", "{%", "{#"].iter().any(|tag| template.contains(tag)) {
+        return Cow::Borrowed(template);
+    }
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name = rest[1..].split('}').next().filter(|name| {
+            rest[1..].contains('}')
+                && !name.is_empty()
+                && !name.contains(|c: char| c == '{' || c.is_whitespace())
+        });
+        match name {
+            Some(name) => {
+                result.push_str(&format!("{{{{ {} }}}}", name));
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    match result == template {
+        true => Cow::Borrowed(template),
+        false => Cow::Owned(result),
+    }
+}
+
+/// 各个事件默认的主题和内容，IP变化使用邮件主题和邮件内容
+fn default_template(config: &Config, kind: EventKind) -> (&str, &str) {
+    match kind {
+        EventKind::IpChanged => (&config.邮件主题, &config.邮件内容),
+        EventKind::Error => ("网络通任务出错", "{{ 错误 }}"),
+        EventKind::Recovered => ("网络通任务恢复正常", "之前的错误: {{ 错误 }}"),
+        EventKind::ExitChanged => (
+            "网络通出口变化",
+            "旧出口: {{ 旧出口 }} 新出口: {{ 新出口 }} 原因: {{ 原因 }}",
+        ),
+        EventKind::LoginFailed => ("网络通登录失败", "{{ 错误 }}"),
+    }
+}
+
+/// 渲染失败时记录日志并使用模板原文，不影响通知
+fn render_or_raw(name: &str, template: &str, context: &Context) -> String {
+    render(name, template, context).unwrap_or_else(|e| {
+        log(format!("渲染{}失败: {:#}", name, e));
+        template.to_owned()
+    })
+}
+
+/// 事件的通知内容，使用"通知模板"中第一个对应这个事件的模板，
+/// 没有时IP变化使用邮件主题和邮件内容，其他事件使用默认模板
+pub fn message(config: &Config, data: &Data, event: &Event) -> Message {
+    let kind = event.kind();
+    let (default_title, default_text) = default_template(config, kind);
+    let template = config
+        .通知模板
+        .iter()
+        .find(|template| template.事件 == kind);
+    let title = template
+        .map(|t| t.主题.as_str())
+        .filter(|t| !t.is_empty())
+        .unwrap_or(default_title);
+    let text = template
+        .map(|t| t.内容.as_str())
+        .filter(|t| !t.is_empty())
+        .unwrap_or(default_text);
+    let context = Context::new(config, data, event);
+    Message {
+        title: render_or_raw("主题", title, &context),
+        text: render_or_raw("内容", text, &context),
+        html: template
+            .filter(|t| !t.HTML内容.is_empty())
+            .map(|t| render_or_raw("HTML内容", &t.HTML内容, &context)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip_changed() -> Event {
+        Event::IpChanged {
+            old_ipv4: "1.1.1.1".to_owned(),
+            new_ipv4: "2.2.2.2".to_owned(),
+            old_ipv6: String::new(),
+            new_ipv6: String::new(),
+        }
+    }
+
+    #[test]
+    fn migrates_legacy_placeholders() {
+        assert_eq!(
+            migrate("旧IPv4: {旧IPv4}\n新IPv4: {新IPv4}\n"),
+            "旧IPv4: {{ 旧IPv4 }}\n新IPv4: {{ 新IPv4 }}\n"
+        );
+        assert!(matches!(migrate("{{ 新IPv4 }} {a}"), Cow::Borrowed(_)));
+        assert!(matches!(migrate("没有占位符 { }"), Cow::Borrowed(_)));
+        assert_eq!(migrate("{新IPv4"), "{新IPv4");
+    }
+
+    #[test]
+    fn reports_unknown_variables_and_syntax_errors() {
+        assert!(validate("邮件内容", &Config::default().邮件内容).is_ok());
+        let e = validate("邮件内容", &migrate("新IPv4: {新IPV4}"))
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "邮件内容中有未知的变量: 新IPV4");
+        let e = validate("邮件内容", "{% if 错误 %}出错").err().unwrap();
+        assert_eq!(e.to_string(), "邮件内容模板有误");
+        let loop_template = "{% for h in 历史 %}{{ h.时间 }} {{ h.IPv4 }}\n{% endfor %}";
+        assert!(validate("邮件内容", loop_template).is_ok());
+    }
+
+    #[test]
+    fn ip_change_uses_mail_template() {
+        let ip_message = message(&Config::default(), &Data::default(), &ip_changed());
+        assert_eq!(ip_message.title, "网络通IP变化通知");
+        assert!(ip_message.text.starts_with("旧IPv4: 1.1.1.1\n"));
+        assert!(ip_message.text.contains("新IPv4: 2.2.2.2\n"));
+        assert_eq!(ip_message.html, None);
+    }
+
+    #[test]
+    fn notify_templates_override_defaults() {
+        let config = Config {
+            机器名: "实验室".to_owned(),
+            网络通出口: 8,
+            通知模板: vec![
+                MessageTemplate {
+                    事件: EventKind::Error,
+                    内容: "{{ 机器名 }}出错: {{ 错误 }}".to_owned(),
+                    ..Default::default()
+                },
+                MessageTemplate {
+                    事件: EventKind::IpChanged,
+                    主题: "{{ 机器名 }}的IP变为{{ 新IPv4 }}".to_owned(),
+                    HTML内容:
+                        "<p>{{ 出口名 }}</p>{% for h in 历史 %}<p>{{ h.IPv4 }}</p>{% endfor %}"
+                            .to_owned(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let mut data = Data::default();
+        data.ipv4 = "<1.1.1.1>".to_owned();
+        data.record_ip_change(Local::now().naive_local());
+
+        let error = Event::Error {
+            error: "连接失败".to_owned(),
+        };
+        let error_message = message(&config, &data, &error);
+        assert_eq!(error_message.title, "网络通任务出错");
+        assert_eq!(error_message.text, "实验室出错: 连接失败");
+
+        let ip_message = message(&config, &data, &ip_changed());
+        assert_eq!(ip_message.title, "实验室的IP变为2.2.2.2");
+        assert!(ip_message.text.starts_with("旧IPv4: 1.1.1.1\n"));
+        assert_eq!(
+            ip_message.html.unwrap(),
+            "<p>移动网出口</p><p>&lt;1.1.1.1&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn keeps_only_recent_history() {
+        let mut data = Data::default();
+        for i in 0..15 {
+            data.ipv4 = format!("10.0.0.{}", i);
+            data.record_ip_change(Local::now().naive_local());
+        }
+        assert_eq!(data.IP历史.len(), 10);
+        assert_eq!(data.IP历史[0].IPv4, "10.0.0.5");
+        assert_eq!(data.IP历史[9].IPv4, "10.0.0.14");
+    }
+}
@@ -1,27 +1,8 @@
 use std::fmt::Display;
 
 use aes_gcm_siv::{aead::Aead, Aes256GcmSiv, Nonce};
-use anyhow::Context;
 use blake2::{Blake2s256, Digest};
 
-pub fn get_str_between(
-    text: &str,
-    left: impl AsRef<str>,
-    right: impl AsRef<str>,
-) -> anyhow::Result<&str> {
-    let left = left.as_ref();
-    let right = right.as_ref();
-    let left = text
-        .find(left)
-        .context(format!("left不在text中\nleft: {}\ntext: {}", left, text))?
-        + left.len();
-    let no_left = &text[left..];
-    let right = no_left
-        .find(right)
-        .context(format!("right不在text中\nright: {}\ntext: {}", right, text))?;
-    Ok(&no_left[..right])
-}
-
 pub fn replace_password(
     text: impl AsRef<str>,
     password: impl AsRef<str>,
@@ -85,6 +66,7 @@ pub fn substr_encrypt(text: impl AsRef<str>, substr: impl AsRef<str>) -> anyhow:
     }
 }
 
+#[allow(clippy::explicit_counter_loop)]
 pub fn print_list(texts: impl IntoIterator<Item = impl Display>, start_index: i32) {
     let mut i = start_index;
     for s in texts {
@@ -119,7 +101,52 @@ pub fn input_key_to_continue(key: &str, text: &str) {
     }
 }
 
-pub fn get_ipv6() -> anyhow::Result<String> {
-    use reqwest::blocking::get;
-    Ok(get("http://api6.ipify.org/")?.text()?)
+/// 从参数列表中取出`--name value`或`--name=value`形式的选项
+pub fn take_option(args: &mut Vec<String>, name: &str) -> anyhow::Result<Option<String>> {
+    let prefix = format!("{name}=");
+    for i in 0..args.len() {
+        if args[i] == name {
+            if i + 1 >= args.len() {
+                anyhow::bail!("{}缺少参数", name);
+            }
+            let value = args.remove(i + 1);
+            args.remove(i);
+            return Ok(Some(value));
+        } else if let Some(value) = args[i].strip_prefix(&prefix) {
+            let value = value.to_owned();
+            args.remove(i);
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// 从参数列表中取出`--name`形式的开关，返回是否存在
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+/// 把"名字: 值"形式的请求头分为名字和值
+pub fn split_header(header: &str) -> anyhow::Result<(&str, &str)> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim(), value.trim())),
+        _ => anyhow::bail!("请求头应为\"名字: 值\"的形式: {}", header),
+    }
+}
+
+/// 本机的主机名，获取失败时为空
+pub fn hostname() -> String {
+    if let Ok(name) = std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")) {
+        return name;
+    }
+    #[cfg(target_os = "linux")]
+    if let Ok(name) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return name.trim().to_owned();
+    }
+    std::process::Command::new("hostname")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_default()
 }
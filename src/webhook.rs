@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::notify::{Event, Message, Notifier};
+use crate::utils::{hostname, split_header};
+
+pub const SIGNATURE_HEADER: &str = "X-Wlt-Signature";
+
+/// config.toml中"Webhook"的一项，通知时向地址POST一个JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Webhook {
+    pub 地址: String,
+    /// 如"Authorization: Bearer xxx"
+    pub 请求头: Vec<String>,
+    /// 不为空时用HMAC-SHA256对请求体签名，放在X-Wlt-Signature请求头中
+    pub 签名密钥: String,
+    pub 重试次数: u32,
+    /// 第一次重试前等待的毫秒数，之后每次翻倍
+    pub 重试间隔: u64,
+    /// 单位为毫秒
+    pub 超时: u64,
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Self {
+            地址: String::new(),
+            请求头: Vec::new(),
+            签名密钥: String::new(),
+            重试次数: 3,
+            重试间隔: 1000,
+            超时: 5000,
+        }
+    }
+}
+
+/// POST的JSON内容，event为事件的英文名，如"ip_changed"
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct WebhookPayload {
+    pub event: String,
+    pub hostname: String,
+    pub timestamp: String,
+    pub title: String,
+    pub message: String,
+    pub old_ipv4: String,
+    pub new_ipv4: String,
+    pub old_ipv6: String,
+    pub new_ipv6: String,
+    pub old_exit: Option<u8>,
+    pub new_exit: Option<u8>,
+    pub error: Option<String>,
+}
+
+impl WebhookPayload {
+    pub fn new(event: &Event, message: &Message) -> Self {
+        let mut payload = Self {
+            event: event.kind().id().to_owned(),
+            hostname: hostname(),
+            timestamp: Local::now().to_rfc3339(),
+            title: message.title.clone(),
+            message: message.text.clone(),
+            ..Default::default()
+        };
+        match event {
+            Event::IpChanged {
+                old_ipv4,
+                new_ipv4,
+                old_ipv6,
+                new_ipv6,
+            } => {
+                payload.old_ipv4 = old_ipv4.clone();
+                payload.new_ipv4 = new_ipv4.clone();
+                payload.old_ipv6 = old_ipv6.clone();
+                payload.new_ipv6 = new_ipv6.clone();
+            }
+            Event::ExitChanged {
+                old_exit, new_exit, ..
+            } => {
+                payload.old_exit = Some(*old_exit);
+                payload.new_exit = Some(*new_exit);
+            }
+            Event::Error { error } | Event::Recovered { error } | Event::LoginFailed { error } => {
+                payload.error = Some(error.clone())
+            }
+        }
+        payload
+    }
+}
+
+/// "sha256="加上请求体的HMAC-SHA256的十六进制
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Webhook {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.地址.trim().is_empty() {
+            anyhow::bail!("Webhook中需要填写地址");
+        }
+        for header in self.请求头.iter() {
+            split_header(header)?;
+        }
+        Ok(())
+    }
+
+    /// 发送body，返回响应的内容，连接失败、超时、5xx和429时重试
+    pub fn send(&self, body: &str) -> anyhow::Result<String> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(self.超时))
+            .build()?;
+        let mut delay = Duration::from_millis(self.重试间隔);
+        let mut attempt = 0;
+        loop {
+            let mut request = client
+                .post(&self.地址)
+                .header("Content-Type", "application/json");
+            for header in self.请求头.iter() {
+                let (name, value) = split_header(header)?;
+                request = request.header(name, value);
+            }
+            if !self.签名密钥.is_empty() {
+                request = request.header(SIGNATURE_HEADER, signature(&self.签名密钥, body));
+            }
+            let (e, retry) = match request.body(body.to_owned()).send() {
+                Ok(response) if response.status().is_success() => {
+                    return Ok(response.text().unwrap_or_default())
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let retry = status.is_server_error()
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    let text = response.text().unwrap_or_default();
+                    (
+                        anyhow::anyhow!("HTTP状态码: {} {}", status, text.trim()),
+                        retry,
+                    )
+                }
+                // 地址中可能包含令牌，不写入日志
+                Err(e) => (e.without_url().into(), true),
+            };
+            if !retry || attempt >= self.重试次数 {
+                return Err(e.context(format!("尝试{}次后仍然失败", attempt + 1)));
+            }
+            attempt += 1;
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    fn notify(&self, event: &Event, message: &Message) -> anyhow::Result<()> {
+        let payload = WebhookPayload::new(event, message);
+        self.send(&serde_json::to_string(&payload)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::mock_gateway::{MockServer, Response};
+    use crate::notify::tests::test_message;
+
+    fn webhook(url: &str) -> Webhook {
+        Webhook {
+            地址: url.to_owned(),
+            重试间隔: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn posts_signed_json() {
+        let server = MockServer::start("/hook", |_| Response::ok(""));
+        let webhook = Webhook {
+            请求头: vec!["Authorization: Bearer token".to_owned()],
+            签名密钥: "secret".to_owned(),
+            ..webhook(&server.url)
+        };
+        webhook.validate().unwrap();
+        let event = Event::IpChanged {
+            old_ipv4: "114.214.180.1".to_owned(),
+            new_ipv4: "114.214.180.23".to_owned(),
+            old_ipv6: String::new(),
+            new_ipv6: String::new(),
+        };
+        webhook.notify(&event, &test_message()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(
+            request.header("x-wlt-signature").unwrap(),
+            signature("secret", &request.body)
+        );
+        let json: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(json["event"], "ip_changed");
+        assert_eq!(json["new_ipv4"], "114.214.180.23");
+        assert_eq!(json["message"], "内容");
+        assert_eq!(json["old_exit"], serde_json::Value::Null);
+        assert_eq!(json["error"], serde_json::Value::Null);
+        assert!(json["timestamp"].as_str().unwrap().contains('T'));
+    }
+
+    #[test]
+    fn signature_matches_known_value() {
+        // echo -n '{"a":1}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            signature("secret", "{\"a\":1}"),
+            "sha256=aa9e2e3575f5d7098b6caccd790888c36d5fdb63342a73bada2d6a51747a8494"
+        );
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let count = Arc::new(AtomicU32::new(0));
+        let server = {
+            let count = count.clone();
+            MockServer::start("/", move |_| match count.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Response::ok("busy").status(503),
+                _ => Response::ok(""),
+            })
+        };
+        webhook(&server.url).send("{}").unwrap();
+        assert_eq!(server.requests().len(), 3);
+
+        let webhook = Webhook {
+            重试次数: 1,
+            ..webhook(&server.url)
+        };
+        count.store(0, Ordering::SeqCst);
+        assert!(webhook.send("{}").is_err());
+        assert_eq!(server.requests().len(), 5);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let server = MockServer::start("/", |_| Response::ok("bad token").status(401));
+        let e = webhook(&server.url).send("{}").err().unwrap();
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(
+            format!("{:#}", e),
+            "尝试1次后仍然失败: HTTP状态码: 401 Unauthorized bad token"
+        );
+    }
+}
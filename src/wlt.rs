@@ -1,15 +1,31 @@
-use std::time::Duration;
+use std::{fmt::Display, net::IpAddr, time::Duration};
 
+use chrono::NaiveDateTime;
 use encoding_rs::GBK;
 use reqwest::{
     blocking::{Client, Response},
     header::{COOKIE, SET_COOKIE},
     StatusCode,
 };
+use scraper::{Html, Selector};
 
-use crate::utils::get_str_between;
+use crate::data::TIME_FORMAT;
+use crate::error::WltError;
 
-const WLT_URL: &str = "http://202.38.64.59/cgi-bin/ip";
+pub const DEFAULT_WLT_URL: &str = "http://202.38.64.59/cgi-bin/ip";
+
+/// 各个出口的名字，编号同网络通出口
+pub const EXIT_NAMES: [&str; 9] = [
+    "教育网出口",
+    "电信网出口",
+    "联通网出口",
+    "电信网出口2",
+    "联通网出口2",
+    "电信网出口3",
+    "联通网出口3",
+    "教育网出口2",
+    "移动网出口",
+];
 
 pub struct WltPage {
     pub url: String,
@@ -22,8 +38,139 @@ pub enum WltPageType {
     ControlPage,
 }
 
+/// 控制页面上显示的当前状态
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlPageInfo {
+    pub ip: String,
+    /// 从0开始，与网络通出口的设置一致
+    pub exit: u8,
+    pub exit_name: String,
+    /// 使用时限，如"永久"、"4小时"
+    pub time_limit: Option<String>,
+    /// 本次开通剩余的时间，如"3小时52分"，使用时限为永久时没有
+    pub remaining: Option<String>,
+    /// 本次开通的到期时间，如"2024-07-15 18:30:00"，使用时限为永久时没有
+    pub expiry: Option<String>,
+    pub account: Option<String>,
+    /// "信息："之后的内容，如"网络设置成功"
+    pub message: Option<String>,
+}
+
+impl Display for ControlPageInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "当前IP地址: {}", self.ip)?;
+        if let Some(account) = &self.account {
+            writeln!(f, "网络通账号: {}", account)?;
+        }
+        write!(f, "出口: {} {}", self.exit, self.exit_name)?;
+        if let Some(time_limit) = &self.time_limit {
+            write!(f, "\n使用时限: {}", time_limit)?;
+        }
+        if let Some(remaining) = &self.remaining {
+            write!(f, "\n剩余时间: {}", remaining)?;
+        }
+        if let Some(expiry) = &self.expiry {
+            write!(f, "\n到期时间: {}", expiry)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, "\n信息: {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl ControlPageInfo {
+    /// 使用时限对应的秒数，永久时为0，与网络通使用时限的设置一致；无法解析时返回None
+    pub fn time_limit_secs(&self) -> Option<u32> {
+        match self.time_limit.as_deref()? {
+            "永久" => Some(0),
+            time_limit => parse_duration(time_limit),
+        }
+    }
+
+    /// 本次开通的到期时间，页面上没有到期时间时按照剩余时间计算
+    pub fn expiry_time(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        if let Some(expiry) = &self.expiry {
+            return NaiveDateTime::parse_from_str(expiry, TIME_FORMAT).ok();
+        }
+        let remaining = parse_duration(self.remaining.as_deref()?)?;
+        Some(now + chrono::Duration::seconds(remaining.into()))
+    }
+}
+
+/// 解析"4小时"、"3小时52分"等时长，返回秒数
+fn parse_duration(text: &str) -> Option<u32> {
+    let mut total = 0u32;
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let digits_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u32 = rest[..digits_len].parse().ok()?;
+        rest = &rest[digits_len..];
+        let (unit, len) = [
+            ("天", 86400),
+            ("小时", 3600),
+            ("分钟", 60),
+            ("分", 60),
+            ("秒", 1),
+        ]
+        .into_iter()
+        .find(|(unit, _)| rest.starts_with(unit))
+        .map(|(unit, secs)| (secs, unit.len()))?;
+        total = total.checked_add(number.checked_mul(unit)?)?;
+        rest = rest[len..].trim_start();
+    }
+    Some(total)
+}
+
+/// 页面中去掉首尾空白后的非空文本节点
+fn text_nodes(html: &Html) -> Vec<&str> {
+    html.root_element()
+        .text()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .collect()
+}
+
+/// 找到包含labels中任意一个的文本节点，返回label之后的内容；label之后没有内容时返回下一个文本节点
+fn field_after<'a>(texts: &[&'a str], labels: &[&str]) -> Option<&'a str> {
+    texts.iter().enumerate().find_map(|(i, text)| {
+        let rest = labels.iter().find_map(|label| text.split_once(label))?.1;
+        let rest = rest.trim_start_matches([':', '：']).trim();
+        if rest.is_empty() {
+            texts.get(i + 1).copied()
+        } else {
+            Some(rest)
+        }
+    })
+}
+
+/// 同一个文本节点中可能有多个字段，用中文逗号分隔
+fn field_value(text: &str) -> &str {
+    text.split(['，', '；']).next().unwrap_or_default().trim()
+}
+
+fn parse_ip(text: &str) -> Option<String> {
+    let ip: String = text
+        .chars()
+        .take_while(|c| c.is_ascii_hexdigit() || *c == '.' || *c == ':')
+        .collect();
+    ip.parse::<IpAddr>().ok().map(|_| ip)
+}
+
+/// 解析"2电信网出口(国际,到教育网走教育网)"，返回从0开始的出口和出口名
+fn parse_exit(text: &str) -> Option<(u8, String)> {
+    let text = field_value(text);
+    let digits_len = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let number: u8 = text[..digits_len].parse().ok()?;
+    Some((number.checked_sub(1)?, text[digits_len..].trim().to_owned()))
+}
+
 impl WltPage {
-    fn new(url: impl Into<String>, resp: Response) -> anyhow::Result<Self> {
+    fn new(url: impl Into<String>, resp: Response) -> Result<Self, WltError> {
         let url = url.into();
         let status = resp.status();
         let text = resp.text_with_charset("GBK")?;
@@ -34,31 +181,87 @@ impl WltPage {
         self.status == StatusCode::OK
     }
 
-    pub fn search_ip(&self) -> anyhow::Result<String> {
-        let ip = match self.page_type()? {
-            WltPageType::LoginPage => get_str_between(&self.text, "name=ip value=", ">"),
-            WltPageType::ControlPage => get_str_between(&self.text, "当前IP地址", "状态"),
-        }?
-        .to_owned();
-        Ok(ip)
+    pub fn search_ip(&self) -> Result<String, WltError> {
+        match self.page_type()? {
+            WltPageType::LoginPage => {
+                let html = Html::parse_document(&self.text);
+                let selector = Selector::parse("input[name=ip]").expect("valid selector");
+                let ip = html
+                    .select(&selector)
+                    .find_map(|input| input.value().attr("value"))
+                    .and_then(parse_ip);
+                ip.ok_or_else(|| {
+                    WltError::ParseError(format!(
+                        "登录页面中没有找到IP地址\nurl: {}\ntext: {}",
+                        self.url, self.text
+                    ))
+                })
+            }
+            WltPageType::ControlPage => Ok(self.control_page_info()?.ip),
+        }
+    }
+
+    /// 解析控制页面上的当前IP、出口、使用时限等信息
+    pub fn control_page_info(&self) -> Result<ControlPageInfo, WltError> {
+        if let WltPageType::LoginPage = self.page_type()? {
+            return Err(WltError::UnknownPage(format!(
+                "当前页面是登录页面，不是控制页面\nurl: {}",
+                self.url
+            )));
+        }
+        let html = Html::parse_document(&self.text);
+        let texts = text_nodes(&html);
+        let missing = |field: &str| {
+            WltError::ParseError(format!(
+                "控制页面中没有找到{}\nurl: {}\ntext: {}",
+                field, self.url, self.text
+            ))
+        };
+
+        let ip = field_after(&texts, &["当前IP地址"])
+            .and_then(parse_ip)
+            .ok_or_else(|| missing("当前IP地址"))?;
+        let (exit, exit_name) = field_after(&texts, &["出口:", "出口："])
+            .and_then(parse_exit)
+            .ok_or_else(|| missing("出口"))?;
+        let time_limit = field_after(&texts, &["使用时限"]).map(|t| field_value(t).to_owned());
+        let remaining = field_after(&texts, &["剩余时间"]).map(|t| field_value(t).to_owned());
+        let expiry = field_after(&texts, &["到期时间"]).map(|t| field_value(t).to_owned());
+        let account = field_after(&texts, &["网络通账号"]).map(|t| field_value(t).to_owned());
+        let message = texts
+            .iter()
+            .find_map(|text| text.strip_prefix("信息"))
+            .map(|t| t.trim_start_matches([':', '：']).trim().to_owned());
+        Ok(ControlPageInfo {
+            ip,
+            exit,
+            exit_name,
+            time_limit,
+            remaining,
+            expiry,
+            account,
+            message,
+        })
     }
 
-    pub fn page_type(&self) -> anyhow::Result<WltPageType> {
+    pub fn page_type(&self) -> Result<WltPageType, WltError> {
         if self.text.contains("网络通账号登录") {
             Ok(WltPageType::LoginPage)
         } else if self.text.contains("访问文献资源建议使用1出口") {
             Ok(WltPageType::ControlPage)
         } else {
-            anyhow::bail!(format!(
+            Err(WltError::UnknownPage(format!(
                 "未知类型页面\nurl: {}\ntext: {}",
                 self.url, self.text
-            ))
+            )))
         }
     }
 }
 
 pub struct WltClient {
     client: Client,
+    gateways: Vec<String>,
+    gateway_index: usize,
     name: String,
     password: String,
     type_: u8,
@@ -67,13 +270,30 @@ pub struct WltClient {
 }
 
 impl WltClient {
-    pub fn new(name: &str, password: &str, type_: u8, exp: u32, rn: &str) -> anyhow::Result<Self> {
+    /// gateways为按顺序尝试的网关，preferred_gateway为上次成功访问的网关，会被最先尝试
+    pub fn new(
+        gateways: &[String],
+        preferred_gateway: &str,
+        name: &str,
+        password: &str,
+        type_: u8,
+        exp: u32,
+        rn: &str,
+    ) -> Result<Self, WltError> {
+        if gateways.is_empty() {
+            return Err(WltError::Config(anyhow::anyhow!("没有设置网络通网关")));
+        }
         let client = reqwest::blocking::Client::builder()
             .timeout(Duration::from_secs(5))
             .no_proxy()
             .build()?;
         Ok(Self {
             client,
+            gateways: gateways.to_vec(),
+            gateway_index: gateways
+                .iter()
+                .position(|gateway| gateway == preferred_gateway)
+                .unwrap_or(0),
             name: name.to_owned(),
             password: password.to_owned(),
             type_,
@@ -82,6 +302,15 @@ impl WltClient {
         })
     }
 
+    #[cfg(test)]
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), WltError> {
+        self.client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .no_proxy()
+            .build()?;
+        Ok(())
+    }
+
     pub fn get_cookie(&self) -> String {
         let password = urlencoding::encode(&self.password);
         let mut cookie = format!("name={}; password={}", self.name, password);
@@ -96,9 +325,47 @@ impl WltClient {
         &self.rn
     }
 
-    fn update_rn(&mut self, resp: &Response) -> anyhow::Result<()> {
+    pub fn get_exp(&self) -> u32 {
+        self.exp
+    }
+
+    /// 修改之后set_wlt使用的出口和使用时限
+    pub fn set_exit(&mut self, type_: u8, exp: u32) {
+        self.type_ = type_;
+        self.exp = exp;
+    }
+
+    /// 最近一次成功访问的网关
+    pub fn get_gateway(&self) -> &str {
+        &self.gateways[self.gateway_index]
+    }
+
+    /// 从最近一次成功访问的网关开始依次尝试，连接失败或超时时尝试下一个网关
+    fn with_gateways<T>(
+        &mut self,
+        mut f: impl FnMut(&mut Self, &str) -> Result<T, WltError>,
+    ) -> Result<T, WltError> {
+        let mut last_error = None;
+        for i in 0..self.gateways.len() {
+            let index = (self.gateway_index + i) % self.gateways.len();
+            let gateway = self.gateways[index].clone();
+            match f(self, &gateway) {
+                Ok(t) => {
+                    self.gateway_index = index;
+                    return Ok(t);
+                }
+                Err(e) if e.is_connection_error() => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.expect("gateways is not empty"))
+    }
+
+    fn update_rn(&mut self, resp: &Response) -> Result<(), WltError> {
         if let Some(set_cookie) = resp.headers().get(SET_COOKIE) {
-            let set_cookie = set_cookie.to_str()?;
+            let set_cookie = set_cookie
+                .to_str()
+                .map_err(|e| WltError::ParseError(format!("Set-Cookie有误: {}", e)))?;
             if let Some(rn) = set_cookie.strip_prefix("rn=") {
                 self.rn = rn.to_owned();
             }
@@ -106,28 +373,37 @@ impl WltClient {
         Ok(())
     }
 
-    pub fn access_page(&mut self) -> anyhow::Result<WltPage> {
-        let resp = self
-            .client
-            .get(WLT_URL)
-            .header(COOKIE, self.get_cookie())
-            .send()?;
-        let wlt_page = WltPage::new(WLT_URL, resp)?;
-        if wlt_page.check_ok() {
-            Ok(wlt_page)
-        } else {
-            anyhow::bail!(format!(
-                "访问网络通页面失败\nurl: {}\nstatus: {}\ntext: {}",
-                WLT_URL, wlt_page.status, wlt_page.text
-            ))
-        }
+    pub fn access_page(&mut self) -> Result<WltPage, WltError> {
+        self.with_gateways(|wlt_client, gateway| {
+            let resp = wlt_client
+                .client
+                .get(gateway)
+                .header(COOKIE, wlt_client.get_cookie())
+                .send()?;
+            let wlt_page = WltPage::new(gateway, resp)?;
+            if wlt_page.check_ok() {
+                Ok(wlt_page)
+            } else {
+                Err(WltError::UnknownPage(format!(
+                    "访问网络通页面失败\nurl: {}\nstatus: {}\ntext: {}",
+                    gateway, wlt_page.status, wlt_page.text
+                )))
+            }
+        })
+    }
+
+    pub fn login(&mut self, ip: &str) -> Result<WltPage, WltError> {
+        self.with_gateways(|wlt_client, gateway| wlt_client.login_at(gateway, ip))
     }
 
-    pub fn login(&mut self, ip: &str) -> anyhow::Result<WltPage> {
+    fn login_at(&mut self, gateway: &str, ip: &str) -> Result<WltPage, WltError> {
+        let login_failed = |reason: &str| WltError::LoginFailed {
+            reason: reason.to_owned(),
+        };
         if self.name.is_empty() {
-            anyhow::bail!("输入的用户名为空");
+            return Err(login_failed("输入的用户名为空"));
         } else if self.password.is_empty() {
-            anyhow::bail!("输入的密码为空");
+            return Err(login_failed("输入的密码为空"));
         }
 
         let name = self.name.to_owned();
@@ -145,33 +421,37 @@ impl WltClient {
         ];
         let resp = self
             .client
-            .post(WLT_URL)
+            .post(gateway)
             .form(&login_form)
             .header(COOKIE, self.get_cookie())
             .send()?;
         self.update_rn(&resp)?;
-        let wlt_page = WltPage::new(WLT_URL, resp)?;
+        let wlt_page = WltPage::new(gateway, resp)?;
         for err_str in ["用户名不存在", "用户名或密码错误"] {
             if wlt_page.text.contains(err_str) {
-                anyhow::bail!(err_str);
+                return Err(login_failed(err_str));
             }
         }
         if wlt_page.status != StatusCode::OK {
-            anyhow::bail!(format!(
+            Err(WltError::UnknownPage(format!(
                 "登录账户失败\nurl: {}\nform: {:?}\nstatus: {}\ntext: {}",
-                WLT_URL, login_form, wlt_page.status, wlt_page.text
-            ))
+                gateway, login_form, wlt_page.status, wlt_page.text
+            )))
         } else {
             Ok(wlt_page)
         }
     }
 
-    pub fn set_wlt(&mut self) -> anyhow::Result<WltPage> {
+    pub fn set_wlt(&mut self) -> Result<WltPage, WltError> {
+        self.with_gateways(|wlt_client, gateway| wlt_client.set_wlt_at(gateway))
+    }
+
+    fn set_wlt_at(&mut self, gateway: &str) -> Result<WltPage, WltError> {
         let go = GBK.encode("开通网络").0;
         let go = &urlencoding::encode_binary(&go);
         let url = format!(
             "{}?cmd=set&url=URL&type={}&exp={}&go=+{}+",
-            WLT_URL, self.type_, self.exp, go,
+            gateway, self.type_, self.exp, go,
         );
         let resp = self
             .client
@@ -182,13 +462,349 @@ impl WltClient {
         if wlt_page.text.contains("信息：网络设置成功") {
             Ok(wlt_page)
         } else {
-            anyhow::bail!(format!(
+            Err(WltError::UnknownPage(format!(
                 "开通网络失败\nurl: {}\ncookies: {}\nstatus: {}\ntext: {}",
                 url,
                 self.get_cookie(),
                 wlt_page.status,
                 wlt_page.text
-            ))
+            )))
         }
     }
+
+    /// 断开网络通连接，成功时网关返回登录页面
+    pub fn logout(&mut self) -> Result<WltPage, WltError> {
+        self.with_gateways(|wlt_client, gateway| wlt_client.logout_at(gateway))
+    }
+
+    fn logout_at(&mut self, gateway: &str) -> Result<WltPage, WltError> {
+        let url = format!("{}?cmd=logout", gateway);
+        let resp = self
+            .client
+            .get(&url)
+            .header(COOKIE, self.get_cookie())
+            .send()?;
+        let wlt_page = WltPage::new(&url, resp)?;
+        if wlt_page.check_ok() && matches!(wlt_page.page_type(), Ok(WltPageType::LoginPage)) {
+            self.rn.clear();
+            Ok(wlt_page)
+        } else {
+            Err(WltError::UnknownPage(format!(
+                "退出网络通失败\nurl: {}\nstatus: {}\ntext: {}",
+                url, wlt_page.status, wlt_page.text
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::mock_gateway::{
+        closed_url, control_page, start_wlt, GatewayState, MockServer, Response,
+    };
+
+    fn client(gateway: &str, name: &str, password: &str) -> WltClient {
+        WltClient::new(&[gateway.to_owned()], "", name, password, 8, 0, "").unwrap()
+    }
+
+    fn page(text: &str) -> WltPage {
+        WltPage {
+            url: DEFAULT_WLT_URL.to_owned(),
+            status: StatusCode::OK,
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_control_page_fixture() {
+        let page = page(include_str!("../tests/fixtures/control_page.html"));
+        assert_eq!(
+            page.control_page_info().unwrap(),
+            ControlPageInfo {
+                ip: "114.214.180.23".to_owned(),
+                exit: 1,
+                exit_name: "电信网出口(国际,到教育网走教育网)".to_owned(),
+                time_limit: Some("4小时".to_owned()),
+                remaining: None,
+                expiry: Some("2024-07-15 18:30:00".to_owned()),
+                account: Some("alice".to_owned()),
+                message: None,
+            }
+        );
+        assert_eq!(page.search_ip().unwrap(), "114.214.180.23");
+    }
+
+    #[test]
+    fn parses_remaining_time_fixture() {
+        let page = page(include_str!(
+            "../tests/fixtures/control_page_remaining.html"
+        ));
+        let info = page.control_page_info().unwrap();
+        assert_eq!(info.time_limit.as_deref(), Some("4小时"));
+        assert_eq!(info.remaining.as_deref(), Some("3小时52分"));
+        assert_eq!(info.expiry, None);
+    }
+
+    #[test]
+    fn control_page_durations() {
+        let control_page = include_str!("../tests/fixtures/control_page.html");
+        let now = NaiveDateTime::parse_from_str("2024-07-15 14:38:00", TIME_FORMAT).unwrap();
+        let info = page(control_page).control_page_info().unwrap();
+        assert_eq!(info.time_limit_secs(), Some(14400));
+        assert_eq!(
+            info.expiry_time(now)
+                .unwrap()
+                .format(TIME_FORMAT)
+                .to_string(),
+            "2024-07-15 18:30:00"
+        );
+
+        let remaining = include_str!("../tests/fixtures/control_page_remaining.html");
+        let info = page(remaining).control_page_info().unwrap();
+        assert_eq!(
+            info.expiry_time(now)
+                .unwrap()
+                .format(TIME_FORMAT)
+                .to_string(),
+            "2024-07-15 18:30:00"
+        );
+
+        let info = page(include_str!("../tests/fixtures/set_success_page.html"))
+            .control_page_info()
+            .unwrap();
+        assert_eq!(info.time_limit_secs(), Some(0));
+        assert_eq!(info.expiry_time(now), None);
+
+        assert_eq!(parse_duration("1天2小时3分钟4秒"), Some(93784));
+        assert_eq!(parse_duration("4小时"), Some(14400));
+        assert_eq!(parse_duration("约4小时"), None);
+        assert_eq!(parse_duration("4"), None);
+    }
+
+    #[test]
+    fn parses_set_success_page_fixture() {
+        let page = page(include_str!("../tests/fixtures/set_success_page.html"));
+        let info = page.control_page_info().unwrap();
+        assert_eq!(info.ip, "2001:da8:d800:1::23");
+        assert_eq!(info.exit, 8);
+        assert_eq!(info.exit_name, "移动网出口(国际,无P2P或带宽限制)");
+        assert_eq!(info.time_limit.as_deref(), Some("永久"));
+        assert_eq!(info.remaining, None);
+        assert_eq!(info.expiry, None);
+        assert_eq!(info.message.as_deref(), Some("网络设置成功"));
+    }
+
+    #[test]
+    fn parses_login_page_fixture() {
+        let page = page(include_str!("../tests/fixtures/login_page.html"));
+        assert!(matches!(page.page_type().unwrap(), WltPageType::LoginPage));
+        assert_eq!(page.search_ip().unwrap(), "114.214.180.23");
+        let e = page.control_page_info().err().unwrap();
+        assert!(e.to_string().starts_with("当前页面是登录页面"));
+    }
+
+    #[test]
+    fn parses_label_and_value_in_one_text_node() {
+        let page = page(
+            "<p>当前IP地址：10.0.0.2</p><p>出口: 3联通网出口，使用时限: 永久</p>\
+             <p>访问文献资源建议使用1出口</p>",
+        );
+        let info = page.control_page_info().unwrap();
+        assert_eq!(info.ip, "10.0.0.2");
+        assert_eq!(info.exit, 2);
+        assert_eq!(info.exit_name, "联通网出口");
+        assert_eq!(info.time_limit.as_deref(), Some("永久"));
+        assert_eq!(info.account, None);
+    }
+
+    #[test]
+    fn control_page_missing_fields_are_errors() {
+        let control_page = include_str!("../tests/fixtures/control_page.html");
+
+        let no_exit = page(&control_page.replace("出口: 2电信网出口", "权限变更中"));
+        let e = no_exit.control_page_info().err().unwrap();
+        assert!(e.to_string().starts_with("控制页面中没有找到出口"));
+
+        let bad_exit = page(&control_page.replace("出口: 2电信网出口", "出口: 电信网出口"));
+        let e = bad_exit.control_page_info().err().unwrap();
+        assert!(e.to_string().starts_with("控制页面中没有找到出口"));
+
+        let no_ip = page(&control_page.replace("114.214.180.23", "未知"));
+        let e = no_ip.search_ip().err().unwrap();
+        assert!(e.to_string().starts_with("控制页面中没有找到当前IP地址"));
+    }
+
+    #[test]
+    fn access_page_returns_login_page_when_logged_out() {
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+
+        let page = wlt_client.access_page().unwrap();
+        assert!(matches!(page.page_type().unwrap(), WltPageType::LoginPage));
+        assert_eq!(page.search_ip().unwrap(), state.ip);
+    }
+
+    #[test]
+    fn login_and_set_wlt() {
+        let state = GatewayState::default();
+        let (server, gateway_state) = start_wlt(state.clone());
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+
+        let page = wlt_client.login(&state.ip).unwrap();
+        assert!(matches!(
+            page.page_type().unwrap(),
+            WltPageType::ControlPage
+        ));
+        assert_eq!(wlt_client.get_rn(), state.rn);
+
+        let page = wlt_client.set_wlt().unwrap();
+        assert!(page.text.contains(EXIT_NAMES[8]));
+        assert_eq!(page.search_ip().unwrap(), state.ip);
+        assert_eq!(gateway_state.lock().unwrap().exit, 8);
+
+        let login_request = &server.requests()[0];
+        assert_eq!(login_request.method, "POST");
+        assert_eq!(login_request.param("name").unwrap(), state.name);
+        assert_eq!(login_request.param("password").unwrap(), state.password);
+        let set_request = &server.requests()[1];
+        assert_eq!(set_request.cookie("rn").unwrap(), state.rn);
+    }
+
+    #[test]
+    fn logout_returns_login_page_and_clears_rn() {
+        let state = GatewayState::default();
+        let (server, gateway_state) = start_wlt(state.clone());
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+        wlt_client.login(&state.ip).unwrap();
+
+        let page = wlt_client.logout().unwrap();
+        assert!(matches!(page.page_type().unwrap(), WltPageType::LoginPage));
+        assert_eq!(wlt_client.get_rn(), "");
+        assert!(!gateway_state.lock().unwrap().logged_in);
+        let logout_request = &server.requests()[1];
+        assert_eq!(logout_request.param("cmd").unwrap(), "logout");
+        assert_eq!(logout_request.cookie("rn").unwrap(), state.rn);
+    }
+
+    #[test]
+    fn logout_fails_when_gateway_stays_logged_in() {
+        let state = GatewayState {
+            logged_in: true,
+            ..Default::default()
+        };
+        let server = MockServer::start("/cgi-bin/ip", move |_| {
+            Response::ok(control_page(&state.ip, &state.name, 0, 0, ""))
+        });
+        let mut wlt_client = client(&server.url, "alice", "p@ss word");
+
+        let e = wlt_client.logout().err().unwrap();
+        assert!(e.to_string().starts_with("退出网络通失败"));
+    }
+
+    #[test]
+    fn login_reports_wrong_password() {
+        let state = GatewayState::default();
+        let (server, gateway_state) = start_wlt(state.clone());
+        let mut wlt_client = client(&server.url, &state.name, "wrong");
+
+        let e = wlt_client.login(&state.ip).err().unwrap();
+        assert!(matches!(e, WltError::LoginFailed { .. }));
+        assert_eq!(e.to_string(), "用户名或密码错误");
+        assert!(!gateway_state.lock().unwrap().logged_in);
+    }
+
+    #[test]
+    fn login_reports_unknown_user() {
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let mut wlt_client = client(&server.url, "bob", &state.password);
+
+        let e = wlt_client.login(&state.ip).err().unwrap();
+        assert_eq!(e.to_string(), "用户名不存在");
+    }
+
+    #[test]
+    fn set_wlt_fails_without_login() {
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+
+        let e = wlt_client.set_wlt().err().unwrap();
+        assert!(e.to_string().starts_with("开通网络失败"));
+    }
+
+    #[test]
+    fn unknown_page_is_an_error() {
+        let server = MockServer::start("/cgi-bin/ip", |_| {
+            Response::ok("<html><body>校园网维护中</body></html>")
+        });
+        let state = GatewayState::default();
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+
+        let page = wlt_client.access_page().unwrap();
+        let e = page.page_type().err().unwrap();
+        assert!(matches!(e, WltError::UnknownPage(_)));
+        assert!(e.to_string().starts_with("未知类型页面"));
+    }
+
+    #[test]
+    fn http_error_status_is_an_error() {
+        let server = MockServer::start("/cgi-bin/ip", |_| Response::ok("").status(502));
+        let state = GatewayState::default();
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+
+        let e = wlt_client.access_page().err().unwrap();
+        assert!(e.to_string().starts_with("访问网络通页面失败"));
+    }
+
+    #[test]
+    fn access_page_times_out() {
+        let server = MockServer::start("/cgi-bin/ip", |_| {
+            Response::ok("").delay(Duration::from_secs(2))
+        });
+        let state = GatewayState::default();
+        let mut wlt_client = client(&server.url, &state.name, &state.password);
+        wlt_client.set_timeout(Duration::from_millis(200)).unwrap();
+
+        let e = wlt_client.access_page().err().unwrap();
+        assert!(matches!(e, WltError::Timeout(_)));
+    }
+
+    #[test]
+    fn falls_back_to_next_gateway() {
+        let state = GatewayState::default();
+        let (server, _) = start_wlt(state.clone());
+        let gateways = [closed_url(), server.url.clone()];
+        let mut wlt_client =
+            WltClient::new(&gateways, "", &state.name, &state.password, 8, 0, "").unwrap();
+
+        wlt_client.access_page().unwrap();
+        assert_eq!(wlt_client.get_gateway(), server.url);
+    }
+
+    #[test]
+    fn prefers_last_working_gateway() {
+        let state = GatewayState::default();
+        let (first, _) = start_wlt(state.clone());
+        let (second, _) = start_wlt(state.clone());
+        let gateways = [first.url.clone(), second.url.clone()];
+        let mut wlt_client = WltClient::new(
+            &gateways,
+            &second.url,
+            &state.name,
+            &state.password,
+            8,
+            0,
+            "",
+        )
+        .unwrap();
+
+        wlt_client.access_page().unwrap();
+        assert!(first.requests().is_empty());
+        assert_eq!(second.requests().len(), 1);
+    }
 }